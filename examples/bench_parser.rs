@@ -1,29 +1,117 @@
-use clothespin::parsing::{Token, Tokenizer};
+use clothespin::parsing::{RefTokenizer, Token, TokenRef, Tokenizer, tokenize_parallel};
+use clothespin::profile::{LexerProfile};
 use time::{get_time};
 
 use std::fs::{File};
 use std::io::{Read};
+use std::path::{Path};
 
-fn bench_tokenizer() {
-  let mut f = File::open("test_data/gravity.py").unwrap();
-  let mut s = String::new();
-  f.read_to_string(&mut s).unwrap();
-  drop(f);
-  let n = 1000;
+const SNIPPET: &'static str = "\
+class Body(object):
+    def __init__(self, mass, pos, vel):
+        self.mass = mass  # kg
+        self.pos = pos
+        self.vel = vel
+
+    def step(self, dt, force):
+        acc = [f / self.mass for f in force]
+        self.vel = [v + a * dt for v, a in zip(self.vel, acc)]
+        self.pos = [p + v * dt for p, v in zip(self.pos, self.vel)]
+        return 0x1f + 1.5e-3 * len(self.pos)
+";
+
+const SNIPPET_UNICODE: &'static str = "\
+class Körper(object):
+    def schritt(self, δt, kraft):
+        beschl = [f / self.masse for f in kraft]  # m/s²
+        self.geschw = [v + a * δt for v, a in zip(self.geschw, beschl)]
+        return 0x1f + 1.5e-3 * len(self.ort)
+";
+
+fn bench_tokens(label: &str, s: &str, n: usize) {
   let t0 = get_time();
+  let mut ntoks = 0;
   for _ in 0 .. n {
-    let toks = Tokenizer::new(&s);
-    for _ in toks {
+    let toks = Tokenizer::new(s);
+    for (_, tok) in toks {
       //println!("DEBUG:  test: {:?}", tok);
+      if let Token::_Eof = tok {
+        break;
+      }
+      ntoks += 1;
+    }
+  }
+  let t1 = get_time();
+  let dt = (t1-t0).to_f64();
+  let r = (s.len() * n) as f64 / dt;
+  println!("DEBUG: bench: {}: len = {}, toks = {}, elapsed = {:.06} s, n = {}, rate = {}",
+      label, s.len(), ntoks / n, dt, n, r as i64);
+}
+
+fn bench_ref_tokens(label: &str, s: &str, n: usize) {
+  let t0 = get_time();
+  let mut ntoks = 0;
+  for _ in 0 .. n {
+    let toks = RefTokenizer::new(s);
+    for (_, tok) in toks {
+      if let TokenRef::Owned(Token::_Eof) = tok {
+        break;
+      }
+      ntoks += 1;
     }
   }
   let t1 = get_time();
   let dt = (t1-t0).to_f64();
   let r = (s.len() * n) as f64 / dt;
-  println!("DEBUG: bench: tokenizer: len = {}, elapsed = {:.06} s, n = {}, rate = {}",
-      s.len(), dt, n, r as i64);
+  println!("DEBUG: bench: {}: len = {}, toks = {}, elapsed = {:.06} s, n = {}, rate = {}",
+      label, s.len(), ntoks / n, dt, n, r as i64);
+}
+
+fn bench_tokenizer() {
+  let path = "test_data/gravity.py";
+  if !Path::new(path).exists() {
+    println!("DEBUG: bench: tokenizer: skipped, missing {}", path);
+    return;
+  }
+  let mut f = File::open(path).unwrap();
+  let mut s = String::new();
+  f.read_to_string(&mut s).unwrap();
+  drop(f);
+  bench_tokens("tokenizer", &s, 1000);
+}
+
+fn bench_tokenizer_ascii() {
+  let s = SNIPPET.repeat(100);
+  bench_tokens("tokenizer (ascii)", &s, 200);
+}
+
+fn bench_ref_tokenizer_ascii() {
+  let s = SNIPPET.repeat(100);
+  bench_ref_tokens("ref tokenizer (ascii)", &s, 200);
+}
+
+fn bench_tokenize_parallel() {
+  let s = SNIPPET.repeat(20000);
+  for &nthreads in [1, 2, 4, 8].iter() {
+    let t0 = get_time();
+    let toks = tokenize_parallel(LexerProfile::default(), &s, nthreads);
+    let t1 = get_time();
+    let dt = (t1-t0).to_f64();
+    let r = s.len() as f64 / dt;
+    println!("DEBUG: bench: parallel: len = {}, toks = {}, elapsed = {:.06} s, threads = {}, rate = {}",
+        s.len(), toks.len(), dt, nthreads, r as i64);
+  }
+}
+
+fn bench_tokenizer_unicode() {
+  let s = SNIPPET_UNICODE.repeat(200);
+  bench_tokens("tokenizer (unicode)", &s, 200);
 }
 
 pub fn main() {
   bench_tokenizer();
+  bench_tokenizer_ascii();
+  bench_ref_tokenizer_ascii();
+  bench_tokenizer_unicode();
+  bench_tokenize_parallel();
 }
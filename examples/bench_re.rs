@@ -0,0 +1,76 @@
+use clothespin::re::{ReMultiMap, ReRangeMap};
+use time::{get_time};
+
+// A small LCG, so that the tables are the same from run to run.
+fn next_rand(x: &mut u64) -> u32 {
+  *x = x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+  (*x >> 33) as u32
+}
+
+fn rand_char(x: &mut u64, bound: u32) -> char {
+  loop {
+    if let Some(c) = char::from_u32(next_rand(x) % bound) {
+      return c;
+    }
+  }
+}
+
+fn bench_range_map() {
+  // NB: roughly the shape of a root node with a few large Unicode classes
+  // (e.g. `XID_Continue`) and many small ones.
+  let mut seed = 1;
+  let mut multi: ReMultiMap<(char, char), usize> = ReMultiMap::default();
+  let mut range: ReRangeMap<usize> = ReRangeMap::default();
+  for eidx in 0 .. 64 {
+    let nranges = if eidx % 16 == 0 { 700 } else { 4 };
+    let mut ranges = Vec::new();
+    for _ in 0 .. nranges {
+      let lb = rand_char(&mut seed, 0x30000);
+      let ub = char::from_u32(lb as u32 + next_rand(&mut seed) % 64).unwrap_or(lb);
+      ranges.push((lb, ub));
+    }
+    ranges.sort();
+    for &r in ranges.iter() {
+      multi.insert(r, eidx);
+      range.insert(r, eidx);
+    }
+  }
+  let queries: Vec<char> = (0 .. 10000).map(|_| rand_char(&mut seed, 0x30000)).collect();
+  let n = 100;
+
+  let t0 = get_time();
+  let mut hits = 0;
+  for _ in 0 .. n {
+    for &c in queries.iter() {
+      for (lb, ub) in multi.keys() {
+        if ub < c {
+          continue;
+        } else if c < lb {
+          break;
+        }
+        hits += multi.values((lb, ub)).count();
+      }
+    }
+  }
+  let t1 = get_time();
+  let dt = (t1-t0).to_f64();
+  println!("DEBUG: bench: multi map: queries = {}, hits = {}, elapsed = {:.06} s, rate = {}",
+      queries.len() * n, hits, dt, ((queries.len() * n) as f64 / dt) as i64);
+
+  let t0 = get_time();
+  let mut hits2 = 0;
+  for _ in 0 .. n {
+    for &c in queries.iter() {
+      hits2 += range.values_at(c).len();
+    }
+  }
+  let t1 = get_time();
+  let dt = (t1-t0).to_f64();
+  println!("DEBUG: bench: range map: queries = {}, hits = {}, elapsed = {:.06} s, rate = {}",
+      queries.len() * n, hits2, dt, ((queries.len() * n) as f64 / dt) as i64);
+  assert_eq!(hits, hits2);
+}
+
+pub fn main() {
+  bench_range_map();
+}
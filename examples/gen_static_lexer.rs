@@ -0,0 +1,10 @@
+use clothespin::profile::{gen_static_lexer};
+
+use std::fs::{File};
+use std::io::{Write};
+
+pub fn main() {
+  let src = gen_static_lexer();
+  let mut f = File::create("src/static_lexer.rs").unwrap();
+  f.write_all(src.as_bytes()).unwrap();
+}
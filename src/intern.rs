@@ -0,0 +1,183 @@
+use crate::str_util::{SafeStr};
+
+use smol_str::{SmolStr};
+
+use std::cell::{RefCell};
+use std::collections::{HashMap};
+use std::rc::{Rc};
+use std::sync::{Arc, RwLock};
+
+/// An interned string, as its index in the interner.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Symbol(pub u32);
+
+impl Symbol {
+  pub fn index(&self) -> usize {
+    self.0 as usize
+  }
+}
+
+/// A table of interned strings. Interning the same string twice gives the
+/// same `Symbol`, and symbols are numbered in order of first interning.
+pub trait Intern {
+  fn intern(&self, s: &str) -> Symbol;
+
+  /// The symbol of `s`, if it has been interned.
+  fn get(&self, s: &str) -> Option<Symbol>;
+
+  fn resolve(&self, sym: Symbol) -> Option<SafeStr>;
+
+  fn len(&self) -> usize;
+
+  fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+impl<I: Intern + ?Sized> Intern for &I {
+  fn intern(&self, s: &str) -> Symbol {
+    (**self).intern(s)
+  }
+
+  fn get(&self, s: &str) -> Option<Symbol> {
+    (**self).get(s)
+  }
+
+  fn resolve(&self, sym: Symbol) -> Option<SafeStr> {
+    (**self).resolve(sym)
+  }
+
+  fn len(&self) -> usize {
+    (**self).len()
+  }
+}
+
+impl<I: Intern + ?Sized> Intern for Rc<I> {
+  fn intern(&self, s: &str) -> Symbol {
+    (**self).intern(s)
+  }
+
+  fn get(&self, s: &str) -> Option<Symbol> {
+    (**self).get(s)
+  }
+
+  fn resolve(&self, sym: Symbol) -> Option<SafeStr> {
+    (**self).resolve(sym)
+  }
+
+  fn len(&self) -> usize {
+    (**self).len()
+  }
+}
+
+impl<I: Intern + ?Sized> Intern for Arc<I> {
+  fn intern(&self, s: &str) -> Symbol {
+    (**self).intern(s)
+  }
+
+  fn get(&self, s: &str) -> Option<Symbol> {
+    (**self).get(s)
+  }
+
+  fn resolve(&self, sym: Symbol) -> Option<SafeStr> {
+    (**self).resolve(sym)
+  }
+
+  fn len(&self) -> usize {
+    (**self).len()
+  }
+}
+
+#[derive(Default, Debug)]
+struct InternTable {
+  map:  HashMap<SmolStr, Symbol>,
+  strs: Vec<SmolStr>,
+}
+
+impl InternTable {
+  fn get(&self, s: &str) -> Option<Symbol> {
+    self.map.get(s).copied()
+  }
+
+  fn insert(&mut self, s: &str) -> Symbol {
+    if let Some(sym) = self.get(s) {
+      return sym;
+    }
+    let sym = Symbol(self.strs.len() as u32);
+    let s = SmolStr::from(s);
+    self.map.insert(s.clone(), sym);
+    self.strs.push(s);
+    sym
+  }
+
+  fn resolve(&self, sym: Symbol) -> Option<SafeStr> {
+    self.strs.get(sym.index()).map(|s| s.clone().into())
+  }
+}
+
+/// An interner for use on one thread, e.g. shared by tokenizers in an
+/// `Rc`.
+#[derive(Default, Debug)]
+pub struct Interner {
+  table:    RefCell<InternTable>,
+}
+
+impl Interner {
+  pub fn new() -> Interner {
+    Interner::default()
+  }
+}
+
+impl Intern for Interner {
+  fn intern(&self, s: &str) -> Symbol {
+    self.table.borrow_mut().insert(s)
+  }
+
+  fn get(&self, s: &str) -> Option<Symbol> {
+    self.table.borrow().get(s)
+  }
+
+  fn resolve(&self, sym: Symbol) -> Option<SafeStr> {
+    self.table.borrow().resolve(sym)
+  }
+
+  fn len(&self) -> usize {
+    self.table.borrow().strs.len()
+  }
+}
+
+/// A thread-safe interner, to be shared by tokenizers on several threads
+/// in an `Arc`, so that their symbols agree.
+#[derive(Default, Debug)]
+pub struct SyncInterner {
+  table:    RwLock<InternTable>,
+}
+
+impl SyncInterner {
+  pub fn new() -> SyncInterner {
+    SyncInterner::default()
+  }
+}
+
+impl Intern for SyncInterner {
+  fn intern(&self, s: &str) -> Symbol {
+    // NB: most strings are interned already, which only needs the read
+    // lock.
+    if let Some(sym) = self.get(s) {
+      return sym;
+    }
+    self.table.write().unwrap().insert(s)
+  }
+
+  fn get(&self, s: &str) -> Option<Symbol> {
+    self.table.read().unwrap().get(s)
+  }
+
+  fn resolve(&self, sym: Symbol) -> Option<SafeStr> {
+    self.table.read().unwrap().resolve(sym)
+  }
+
+  fn len(&self) -> usize {
+    self.table.read().unwrap().strs.len()
+  }
+}
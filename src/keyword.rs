@@ -0,0 +1,382 @@
+use crate::parsing::{CharSpan, Token};
+
+use smol_str::{SmolStr};
+
+use std::collections::{VecDeque};
+use std::rc::{Rc};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeywordClass {
+  /// Always lexed as the keyword token.
+  Hard,
+  /// Lexed as `Token::Ident`, and promoted to the keyword token by
+  /// `SoftKeywords` where the context calls for a keyword.
+  Soft,
+}
+
+/// The keywords of a language version, each with its token and whether it
+/// is a hard or a soft keyword.
+#[derive(Clone, Debug)]
+pub struct KeywordTable {
+  entries:  Vec<(SmolStr, Token, KeywordClass)>,
+}
+
+impl Default for KeywordTable {
+  fn default() -> KeywordTable {
+    let mut kws = KeywordTable::empty();
+    kws.insert("True",    Token::True,    KeywordClass::Hard);
+    kws.insert("False",   Token::False,   KeywordClass::Hard);
+    kws.insert("None",    Token::None,    KeywordClass::Hard);
+    kws.insert("and",     Token::And,     KeywordClass::Hard);
+    kws.insert("async",   Token::Async,   KeywordClass::Hard);
+    kws.insert("assert",  Token::Assert,  KeywordClass::Hard);
+    kws.insert("as",      Token::As,      KeywordClass::Hard);
+    kws.insert("await",   Token::Await,   KeywordClass::Hard);
+    kws.insert("break",   Token::Break,   KeywordClass::Hard);
+    kws.insert("case",    Token::Case,    KeywordClass::Soft);
+    kws.insert("class",   Token::Class,   KeywordClass::Hard);
+    kws.insert("continue", Token::Continue, KeywordClass::Hard);
+    kws.insert("def",     Token::Def,     KeywordClass::Hard);
+    kws.insert("del",     Token::Del,     KeywordClass::Hard);
+    kws.insert("elif",    Token::Elif,    KeywordClass::Hard);
+    kws.insert("else",    Token::Else,    KeywordClass::Hard);
+    kws.insert("except",  Token::Except,  KeywordClass::Hard);
+    kws.insert("finally", Token::Finally, KeywordClass::Hard);
+    kws.insert("from",    Token::From,    KeywordClass::Hard);
+    kws.insert("for",     Token::For,     KeywordClass::Hard);
+    kws.insert("global",  Token::Global,  KeywordClass::Hard);
+    kws.insert("import",  Token::Import,  KeywordClass::Hard);
+    kws.insert("if",      Token::If,      KeywordClass::Hard);
+    kws.insert("in",      Token::In,      KeywordClass::Hard);
+    kws.insert("is",      Token::Is,      KeywordClass::Hard);
+    kws.insert("lambda",  Token::Lambda,  KeywordClass::Hard);
+    kws.insert("match",   Token::Match,   KeywordClass::Soft);
+    kws.insert("nonlocal", Token::Nonlocal, KeywordClass::Hard);
+    kws.insert("not",     Token::Not,     KeywordClass::Hard);
+    kws.insert("or",      Token::Or,      KeywordClass::Hard);
+    kws.insert("pass",    Token::Pass,    KeywordClass::Hard);
+    kws.insert("raise",   Token::Raise,   KeywordClass::Hard);
+    kws.insert("return",  Token::Return,  KeywordClass::Hard);
+    kws.insert("try",     Token::Try,     KeywordClass::Hard);
+    kws.insert("type",    Token::Type,    KeywordClass::Soft);
+    kws.insert("where",   Token::Where,   KeywordClass::Hard);
+    kws.insert("while",   Token::While,   KeywordClass::Hard);
+    kws.insert("with",    Token::With,    KeywordClass::Hard);
+    kws.insert("yield",   Token::Yield,   KeywordClass::Hard);
+    kws.insert("_",       Token::Place,   KeywordClass::Soft);
+    kws
+  }
+}
+
+impl KeywordTable {
+  pub fn empty() -> KeywordTable {
+    KeywordTable{entries: Vec::new()}
+  }
+
+  /// Insert a keyword, or replace the token and class of an existing one.
+  pub fn insert<K: Into<SmolStr>>(&mut self, kw: K, tok: Token, class: KeywordClass) {
+    let kw = kw.into();
+    for e in self.entries.iter_mut() {
+      if e.0 == kw {
+        e.1 = tok;
+        e.2 = class;
+        return;
+      }
+    }
+    self.entries.push((kw, tok, class));
+  }
+
+  pub fn remove(&mut self, kw: &str) -> Option<(Token, KeywordClass)> {
+    let idx = self.entries.iter().position(|e| e.0 == kw)?;
+    let (_, tok, class) = self.entries.remove(idx);
+    Some((tok, class))
+  }
+
+  /// Change the class of an existing keyword; returns false if there is no
+  /// such keyword.
+  pub fn set_class(&mut self, kw: &str, class: KeywordClass) -> bool {
+    for e in self.entries.iter_mut() {
+      if e.0 == kw {
+        e.2 = class;
+        return true;
+      }
+    }
+    false
+  }
+
+  pub fn get(&self, kw: &str) -> Option<(&Token, KeywordClass)> {
+    self.entries.iter().find(|e| e.0 == kw).map(|e| (&e.1, e.2))
+  }
+
+  pub fn iter<'a>(&'a self) -> impl Iterator<Item=(&'a str, &'a Token, KeywordClass)> + 'a {
+    self.entries.iter().map(|e| (e.0.as_str(), &e.1, e.2))
+  }
+
+  pub fn hard<'a>(&'a self) -> impl Iterator<Item=(&'a str, &'a Token)> + 'a {
+    self.iter().filter(|e| e.2 == KeywordClass::Hard).map(|e| (e.0, e.1))
+  }
+
+  pub fn soft<'a>(&'a self) -> impl Iterator<Item=(&'a str, &'a Token)> + 'a {
+    self.iter().filter(|e| e.2 == KeywordClass::Soft).map(|e| (e.0, e.1))
+  }
+
+  fn get_soft(&self, kw: &str) -> Option<&Token> {
+    match self.get(kw) {
+      Some((tok, KeywordClass::Soft)) => Some(tok),
+      _ => None
+    }
+  }
+}
+
+thread_local! {
+  static TL_KEYWORDS: Rc<KeywordTable> = Rc::new(KeywordTable::default());
+}
+
+pub fn tl_keyword_table() -> Rc<KeywordTable> {
+  TL_KEYWORDS.with(|kws| kws.clone())
+}
+
+/// Post-pass over a token stream that promotes soft keywords, which the
+/// tokenizer emits as `Token::Ident`, to their keyword tokens by context.
+///
+/// The pass buffers one logical line at a time. The built-in soft keywords
+/// are classified as follows:
+///
+/// - `match`: at the start of a statement, not followed by a token that
+///   continues a name (e.g. `=`, `.`, `,`), and on a line ending with `:`.
+/// - `case`: as `match`, but within the block of a `match` statement and
+///   on a line with a `:` outside of brackets.
+/// - `type`: at the start of a statement and followed by a name, then by
+///   `=` or `[`.
+/// - `_`: within the pattern of a `case` line.
+///
+/// Any other soft keyword is promoted at the start of a statement when not
+/// followed by a token that continues a name.
+pub struct SoftKeywords<I> {
+  inner:    I,
+  kws:      Rc<KeywordTable>,
+  line:     Vec<(CharSpan, Token)>,
+  out:      VecDeque<(CharSpan, Token)>,
+  // Indentation of each enclosing `match` statement.
+  matches:  Vec<u32>,
+  done:     bool,
+}
+
+impl<I> SoftKeywords<I> {
+  pub fn new(inner: I) -> SoftKeywords<I> {
+    SoftKeywords::new2(tl_keyword_table(), inner)
+  }
+
+  pub fn new2(kws: Rc<KeywordTable>, inner: I) -> SoftKeywords<I> {
+    SoftKeywords{
+      inner,
+      kws,
+      line:     Vec::new(),
+      out:      VecDeque::new(),
+      matches:  Vec::new(),
+      done:     false,
+    }
+  }
+}
+
+fn is_trivia(tok: &Token) -> bool {
+  match tok {
+    &Token::Space |
+    &Token::IndentSpace(_) |
+    &Token::CommentNL(_) |
+    &Token::Backslash |
+    &Token::NL => true,
+    _ => false
+  }
+}
+
+/// Whether the token following a word shows that the word is used as a
+/// name, e.g. in `match = 1` or `type.mro()`.
+fn continues_name(next: Option<&Token>) -> bool {
+  match next {
+    None |
+    Some(&Token::Equal) |
+    Some(&Token::PlusEq) |
+    Some(&Token::DashEq) |
+    Some(&Token::StarEq) |
+    Some(&Token::StarStarEq) |
+    Some(&Token::SlashEq) |
+    Some(&Token::SlashSlashEq) |
+    Some(&Token::PercentEq) |
+    Some(&Token::AmpEq) |
+    Some(&Token::BarEq) |
+    Some(&Token::CaretEq) |
+    Some(&Token::LShiftEq) |
+    Some(&Token::RShiftEq) |
+    Some(&Token::AtEq) |
+    Some(&Token::ColonEq) |
+    Some(&Token::Dot) |
+    Some(&Token::Colon) |
+    Some(&Token::Comma) |
+    Some(&Token::Semi) |
+    Some(&Token::RParen) |
+    Some(&Token::RBrack) |
+    Some(&Token::RCurly) |
+    Some(&Token::_Eof) |
+    Some(&Token::_Err) => true,
+    _ => false
+  }
+}
+
+impl<I: Iterator<Item=(CharSpan, Token)>> SoftKeywords<I> {
+  fn _fill_line(&mut self) {
+    let mut depth: u32 = 0;
+    let mut cont = false;
+    loop {
+      let (span, tok) = match self.inner.next() {
+        None => {
+          self.done = true;
+          return;
+        }
+        Some(t) => t
+      };
+      let stop = match &tok {
+        &Token::LParen | &Token::LBrack | &Token::LCurly => {
+          depth += 1;
+          false
+        }
+        &Token::RParen | &Token::RBrack | &Token::RCurly => {
+          depth = depth.saturating_sub(1);
+          false
+        }
+        &Token::NL => depth == 0 && !cont,
+        &Token::_Eof | &Token::_Err => true,
+        _ => false
+      };
+      match &tok {
+        &Token::Space => {}
+        &Token::Backslash => {
+          cont = true;
+        }
+        _ => {
+          cont = false;
+        }
+      }
+      self.line.push((span, tok));
+      if stop {
+        return;
+      }
+    }
+  }
+
+  fn _classify_line(&mut self) {
+    let sig: Vec<usize> = (0 .. self.line.len()).filter(|&i| !is_trivia(&self.line[i].1)).collect();
+    if sig.is_empty() {
+      return;
+    }
+    let indent = match self.line.first() {
+      Some(&(_, Token::IndentSpace(n))) => n,
+      _ => 0
+    };
+    while let Some(&m) = self.matches.last() {
+      if m < indent {
+        break;
+      }
+      self.matches.pop();
+    }
+    // Bracket depth of each significant token, and the positions (in `sig`)
+    // of the colons outside of brackets.
+    let mut depths = Vec::with_capacity(sig.len());
+    let mut colons = Vec::new();
+    let mut depth: u32 = 0;
+    for (k, &i) in sig.iter().enumerate() {
+      match &self.line[i].1 {
+        &Token::LParen | &Token::LBrack | &Token::LCurly => {
+          depths.push(depth);
+          depth += 1;
+        }
+        &Token::RParen | &Token::RBrack | &Token::RCurly => {
+          depth = depth.saturating_sub(1);
+          depths.push(depth);
+        }
+        &Token::Colon => {
+          depths.push(depth);
+          if depth == 0 {
+            colons.push(k);
+          }
+        }
+        _ => {
+          depths.push(depth);
+        }
+      }
+    }
+    let ends_with_colon = colons.last() == Some(&(sig.len() - 1));
+    let mut pattern: Option<(usize, usize)> = None;
+    for k in 0 .. sig.len() {
+      let i = sig[k];
+      let tok = match &self.line[i].1 {
+        &Token::Ident(ref s) => match self.kws.get_soft(s.as_raw_str()) {
+          None => continue,
+          Some(tok) => tok.clone()
+        },
+        _ => continue
+      };
+      let stmt_start = k == 0 || (depths[k - 1] == 0 && self.line[sig[k - 1]].1 == Token::Semi);
+      let next = sig.get(k + 1).map(|&i| &self.line[i].1);
+      let promote = match &tok {
+        &Token::Match => {
+          stmt_start && !continues_name(next) && ends_with_colon
+        }
+        &Token::Case => {
+          let colon = colons.iter().find(|&&c| c > k).map(|&c| c);
+          let promote = stmt_start && !continues_name(next) &&
+                        !self.matches.is_empty() && colon.is_some();
+          if promote {
+            pattern = Some((k, colon.unwrap()));
+          }
+          promote
+        }
+        &Token::Type => {
+          let next2 = sig.get(k + 2).map(|&i| &self.line[i].1);
+          stmt_start &&
+          match next {
+            Some(&Token::Ident(_)) => true,
+            _ => false
+          } &&
+          match next2 {
+            Some(&Token::Equal) | Some(&Token::LBrack) => true,
+            _ => false
+          }
+        }
+        &Token::Place => {
+          match pattern {
+            Some((start, end)) => start < k && k < end,
+            None => false
+          }
+        }
+        _ => {
+          stmt_start && !continues_name(next)
+        }
+      };
+      if promote {
+        if let &Token::Match = &tok {
+          self.matches.push(indent);
+        }
+        self.line[i].1 = tok;
+      }
+    }
+  }
+}
+
+impl<I: Iterator<Item=(CharSpan, Token)>> Iterator for SoftKeywords<I> {
+  type Item = (CharSpan, Token);
+
+  fn next(&mut self) -> Option<(CharSpan, Token)> {
+    loop {
+      if let Some(t) = self.out.pop_front() {
+        return Some(t);
+      }
+      if self.done {
+        return None;
+      }
+      self._fill_line();
+      self._classify_line();
+      self.out.extend(self.line.drain(..));
+    }
+  }
+}
@@ -0,0 +1,157 @@
+use crate::parsing::{CharSpan};
+use crate::re::{ReTrie, len_utf8};
+
+use std::rc::{Rc};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum TokenCategory {
+  Keyword,
+  Name,
+  Literal,
+  Operator,
+  Delimiter,
+  Trivia,
+  Other,
+}
+
+/// Metadata of one rule of a `lexer!` definition.
+#[derive(Clone, Copy, Debug)]
+pub struct LexerRule {
+  pub name:     &'static str,
+  pub pattern:  &'static str,
+  pub category: TokenCategory,
+}
+
+/// Generic tokenizer driven by a `ReTrie`, yielding the longest match at
+/// each position.
+///
+/// If no rule matches, the span of the next char is yielded as an error,
+/// after which the lexer stops.
+pub struct RuleLexer<'t, T> {
+  trie: Rc<ReTrie<T>>,
+  text: &'t str,
+  off:  usize,
+  done: bool,
+}
+
+impl<'t, T> RuleLexer<'t, T> {
+  pub fn new(trie: Rc<ReTrie<T>>, text: &'t str) -> RuleLexer<'t, T> {
+    RuleLexer{trie, text, off: 0, done: false}
+  }
+
+  pub fn position(&self) -> usize {
+    self.off
+  }
+}
+
+impl<'t, T> Iterator for RuleLexer<'t, T> {
+  type Item = Result<(CharSpan, T), CharSpan>;
+
+  fn next(&mut self) -> Option<Result<(CharSpan, T), CharSpan>> {
+    if self.done || self.off >= self.text.len() {
+      return None;
+    }
+    let start = self.off;
+    match self.trie.match_at(self.text, start) {
+      // NB: an empty match would never advance, so it is an error too.
+      None => {}
+      Some((_, end)) if end == start => {}
+      Some((tok, end)) => {
+        self.off = end;
+        return Some(Ok((CharSpan{start, end}, tok)));
+      }
+    }
+    self.done = true;
+    let c = self.text[start .. ].chars().next().unwrap();
+    let end = start + len_utf8(c as _);
+    Some(Err(CharSpan{start, end}))
+  }
+}
+
+/// Define a token enum along with its `ReTrie` and per-token metadata.
+///
+/// Each rule gives a variant, its category (a `TokenCategory` variant), its
+/// pattern, and for variants with fields, the function that constructs the
+/// token from the matched text:
+///
+/// ```ignore
+/// lexer! {
+///   #[derive(Clone, PartialEq, Debug)]
+///   pub enum CalcToken {
+///     Space: Trivia = r"[ \t]+",
+///     Int(SafeStr): Literal = r"[0-9]+" => |s| CalcToken::Int(s.into()),
+///     Plus: Operator = r"\+",
+///   }
+/// }
+/// ```
+///
+/// As with `ReTrie`, the longest match wins, and ties go to the earlier
+/// rule. The generated `impl` provides `RULES`, `fresh_trie`, `registry`,
+/// `tl_trie`, `lex`, `name`, and `category`.
+#[macro_export]
+macro_rules! lexer {
+  (@fun $name:ident $var:ident) => {
+    |_: &str| $name::$var
+  };
+  (@fun $name:ident $var:ident $fun:expr) => {
+    $fun
+  };
+  (
+    $(#[$meta:meta])*
+    $vis:vis enum $name:ident {
+      $( $var:ident $( ( $($fty:ty),+ ) )? : $cat:ident = $re:literal $( => $fun:expr )? ),+ $(,)?
+    }
+  ) => {
+    $(#[$meta])*
+    $vis enum $name {
+      $( $var $( ( $($fty),+ ) )? ),+
+    }
+
+    impl $name {
+      pub const RULES: &'static [$crate::lexer::LexerRule] = &[
+        $( $crate::lexer::LexerRule{
+          name:     stringify!($var),
+          pattern:  $re,
+          category: $crate::lexer::TokenCategory::$cat,
+        } ),+
+      ];
+
+      pub fn fresh_trie() -> $crate::re::ReTrie<$name> {
+        let mut tr = $crate::re::ReTrie::default();
+        $( tr.push($re, $crate::lexer!(@fun $name $var $($fun)?)); )+
+        tr
+      }
+
+      /// The map functions of the rules, keyed by variant name, for
+      /// loading a trie image written with the `RULES` names as rule ids.
+      pub fn registry() -> $crate::re::ReRegistry<$name> {
+        let mut reg = $crate::re::ReRegistry::default();
+        $( reg.insert(stringify!($var), $crate::lexer!(@fun $name $var $($fun)?)); )+
+        reg
+      }
+
+      pub fn tl_trie() -> ::std::rc::Rc<$crate::re::ReTrie<$name>> {
+        ::std::thread_local! {
+          static TL_TRIE: ::std::rc::Rc<$crate::re::ReTrie<$name>> = ::std::rc::Rc::new($name::fresh_trie());
+        }
+        TL_TRIE.with(|trie| trie.clone())
+      }
+
+      pub fn lex<'t>(text: &'t str) -> $crate::lexer::RuleLexer<'t, $name> {
+        $crate::lexer::RuleLexer::new($name::tl_trie(), text)
+      }
+
+      pub fn name(&self) -> &'static str {
+        match self {
+          $( &$name::$var { .. } => stringify!($var) ),+
+        }
+      }
+
+      pub fn category(&self) -> $crate::lexer::TokenCategory {
+        match self {
+          $( &$name::$var { .. } => $crate::lexer::TokenCategory::$cat ),+
+        }
+      }
+    }
+  };
+}
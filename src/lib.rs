@@ -1,7 +1,15 @@
+#[cfg(feature = "regex")]
 extern crate regex_syntax;
 extern crate smol_str;
+extern crate unicode_normalization;
 
+pub mod intern;
+pub mod keyword;
 pub mod legacy;
+pub mod lexer;
 pub mod parsing;
+pub mod profile;
 pub mod re;
+pub mod source;
+pub mod static_lexer;
 pub mod str_util;
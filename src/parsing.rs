@@ -1,10 +1,15 @@
-use crate::re::{ReTrie, len_utf8};
-use crate::str_util::{SafeStr, unescape_str};
+use crate::intern::{Intern, Interner, Symbol};
+use crate::keyword::{KeywordTable};
+use crate::lexer::{TokenCategory};
+use crate::profile::{LexerProfile, tl_profile_trie};
+use crate::re::{ReRules, ReTrie, RuleId, re_escape};
+use crate::source::{SourceError, SourceText};
+use crate::str_util::{SafeStr, nfkc_ident, scan_str, unescape_str};
 
 use std::fmt::{Debug, Formatter, Result as FmtResult};
 use std::iter::{Peekable};
-use std::mem::{replace};
 use std::rc::{Rc};
+use std::thread::{scope};
 
 #[derive(Clone, Copy)]
 pub struct CharSpan {
@@ -34,125 +39,241 @@ impl CharSpan {
   }
 }
 
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
-pub enum Token {
-  Space,
-  NL,
-  CR,
-  IndentSpace(u32),
-  CommentNL(SafeStr),
-  Comma,
-  Dot,
-  DotDotDot,
-  Semi,
-  SemiSemi,
-  Colon,
-  ColonColon,
-  Query,
-  Bang,
-  Dash,
-  DashEq,
-  Plus,
+/// The original form of a newline, which is normalized to `Token::NL`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NewlineForm {
+  Lf,
+  CrLf,
+  Cr,
+}
+
+impl NewlineForm {
+  /// Classify the source text of a `Token::NL` span.
+  pub fn of(s: &str) -> Option<NewlineForm> {
+    match s {
+      "\n" => Some(NewlineForm::Lf),
+      "\r\n" => Some(NewlineForm::CrLf),
+      "\r" => Some(NewlineForm::Cr),
+      _ => None
+    }
+  }
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      &NewlineForm::Lf => "\n",
+      &NewlineForm::CrLf => "\r\n",
+      &NewlineForm::Cr => "\r",
+    }
+  }
+}
+
+/// Defines `Token` and `TokenKind` from one list of variants, each with
+/// the type of its payload, if any, and its `TokenCategory`.
+macro_rules! tokens {
+  (@bare $var:ident) => {
+    Some(Token::$var)
+  };
+  (@bare $var:ident $fty:ty) => {
+    None
+  };
+  (@table $var:ident $payload:ident $intern:ident) => {
+    Token::$var
+  };
+  (@table $var:ident $payload:ident $intern:ident $fty:ty) => {
+    Token::$var(<$fty as TablePayload>::from_table($payload, $intern))
+  };
+  (
+    $( $var:ident $( ( $fty:ty ) )? : $cat:ident ),+ $(,)?
+  ) => {
+    #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+    pub enum Token {
+      $( $var $( ( $fty ) )? ),+
+    }
+
+    impl Token {
+      pub fn kind(&self) -> TokenKind {
+        match self {
+          $( &Token::$var { .. } => TokenKind::$var ),+
+        }
+      }
+    }
+
+    /// The kind of a `Token`, without its payload.
+    #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+    #[repr(u8)]
+    pub enum TokenKind {
+      $( $var ),+
+    }
+
+    impl TokenKind {
+      pub fn category(&self) -> TokenCategory {
+        match *self {
+          $( TokenKind::$var => TokenCategory::$cat ),+
+        }
+      }
+
+      /// The token of this kind, if it has no payload.
+      pub fn bare(&self) -> Option<Token> {
+        match *self {
+          $( TokenKind::$var => tokens!(@bare $var $($fty)?) ),+
+        }
+      }
+
+      /// The token of this kind with a payload as stored in a
+      /// `TokenTable`.
+      fn _from_table(&self, payload: u32, intern: &dyn Intern) -> Token {
+        match *self {
+          $( TokenKind::$var => tokens!(@table $var payload intern $($fty)?) ),+
+        }
+      }
+    }
+  };
+}
+
+/// A payload of a `Token`, as stored in a `TokenTable`.
+trait TablePayload {
+  fn from_table(payload: u32, intern: &dyn Intern) -> Self;
+}
+
+impl TablePayload for u32 {
+  fn from_table(payload: u32, _intern: &dyn Intern) -> u32 {
+    payload
+  }
+}
+
+impl TablePayload for SafeStr {
+  fn from_table(payload: u32, intern: &dyn Intern) -> SafeStr {
+    intern.resolve(Symbol(payload)).unwrap()
+  }
+}
+
+tokens! {
+  Space: Trivia,
+  NL: Trivia,
+  IndentSpace(u32): Trivia,
+  CommentNL(SafeStr): Trivia,
+  Comma: Delimiter,
+  Dot: Delimiter,
+  DotDotDot: Operator,
+  Semi: Delimiter,
+  SemiSemi: Delimiter,
+  Colon: Delimiter,
+  ColonColon: Operator,
+  ColonEq: Operator,
+  Query: Operator,
+  Bang: Operator,
+  Dash: Operator,
+  DashEq: Operator,
+  Plus: Operator,
   //PlusPlus,
-  PlusEq,
-  Star,
-  StarStar,
-  StarEq,
-  Slash,
-  SlashEq,
-  SlashSlash,
-  Backslash,
-  Percent,
-  PercentEq,
-  Amp,
-  AmpEq,
-  Bar,
-  BarEq,
-  Caret,
-  Tilde,
-  At,
-  LShift,
-  LShiftEq,
-  RShift,
-  RShiftEq,
-  Equal,
-  EqEq,
-  Neq,
-  Geq,
-  Gt,
-  Leq,
-  Lt,
-  XNot,
-  XNeq,
-  LDash,
-  RDash,
-  LEqual,
-  REqual,
-  LTilde,
-  RTilde,
-  LQueryDash,
-  LQueryTilde,
-  LBangDash,
-  LBangTilde,
-  LArrow,
-  RArrow,
-  REqArrow,
-  LTildeArrow,
-  RTildeArrow,
-  LParen,
-  RParen,
-  LBrack,
-  RBrack,
-  LCurly,
-  RCurly,
-  True,
-  False,
-  None,
-  And,
-  As,
-  Assert,
-  Async,
-  Await,
-  Break,
-  Case,
-  Class,
-  Continue,
-  Def,
-  Del,
-  Elif,
-  Else,
-  Except,
-  Finally,
-  For,
-  From,
-  Global,
-  Import,
-  If,
-  In,
-  Is,
-  Lambda,
-  Match,
-  Nonlocal,
-  Not,
-  Or,
-  Pass,
-  Raise,
-  Return,
-  Try,
-  Type,
-  Where,
-  While,
-  With,
-  Yield,
-  Place,
-  Int(SafeStr),
-  Lit(SafeStr),
-  Ident(SafeStr),
-  _Eof,
-  _Bot,
+  PlusEq: Operator,
+  Star: Operator,
+  StarStar: Operator,
+  StarStarEq: Operator,
+  StarEq: Operator,
+  Slash: Operator,
+  SlashEq: Operator,
+  SlashSlash: Operator,
+  SlashSlashEq: Operator,
+  Backslash: Trivia,
+  Percent: Operator,
+  PercentEq: Operator,
+  Amp: Operator,
+  AmpEq: Operator,
+  Bar: Operator,
+  BarEq: Operator,
+  Caret: Operator,
+  CaretEq: Operator,
+  Tilde: Operator,
+  At: Operator,
+  AtEq: Operator,
+  LShift: Operator,
+  LShiftEq: Operator,
+  RShift: Operator,
+  RShiftEq: Operator,
+  Equal: Operator,
+  EqEq: Operator,
+  Neq: Operator,
+  Geq: Operator,
+  Gt: Operator,
+  Leq: Operator,
+  Lt: Operator,
+  XNot: Operator,
+  XNeq: Operator,
+  LDash: Operator,
+  RDash: Operator,
+  LEqual: Operator,
+  REqual: Operator,
+  LTilde: Operator,
+  RTilde: Operator,
+  LQueryDash: Operator,
+  LQueryTilde: Operator,
+  LBangDash: Operator,
+  LBangTilde: Operator,
+  LArrow: Operator,
+  RArrow: Operator,
+  REqArrow: Operator,
+  LTildeArrow: Operator,
+  RTildeArrow: Operator,
+  LParen: Delimiter,
+  RParen: Delimiter,
+  LBrack: Delimiter,
+  RBrack: Delimiter,
+  LCurly: Delimiter,
+  RCurly: Delimiter,
+  True: Keyword,
+  False: Keyword,
+  None: Keyword,
+  And: Keyword,
+  As: Keyword,
+  Assert: Keyword,
+  Async: Keyword,
+  Await: Keyword,
+  Break: Keyword,
+  Case: Keyword,
+  Class: Keyword,
+  Continue: Keyword,
+  Def: Keyword,
+  Del: Keyword,
+  Elif: Keyword,
+  Else: Keyword,
+  Except: Keyword,
+  Finally: Keyword,
+  For: Keyword,
+  From: Keyword,
+  Global: Keyword,
+  Import: Keyword,
+  If: Keyword,
+  In: Keyword,
+  Is: Keyword,
+  Lambda: Keyword,
+  Match: Keyword,
+  Nonlocal: Keyword,
+  Not: Keyword,
+  Or: Keyword,
+  Pass: Keyword,
+  Raise: Keyword,
+  Return: Keyword,
+  Try: Keyword,
+  Type: Keyword,
+  Where: Keyword,
+  While: Keyword,
+  With: Keyword,
+  Yield: Keyword,
+  Place: Keyword,
+  Int(SafeStr): Literal,
+  Lit(SafeStr): Literal,
+  Ident(SafeStr): Name,
+  _Err: Other,
+  _Eof: Other,
+  _Bot: Other,
 }
 
 impl Token {
+  pub fn category(&self) -> TokenCategory {
+    self.kind().category()
+  }
+
   pub fn is_eof(&self) -> bool {
     match self {
       &Token::_Eof => true,
@@ -160,6 +281,13 @@ impl Token {
     }
   }
 
+  pub fn is_err(&self) -> bool {
+    match self {
+      &Token::_Err => true,
+      _ => false
+    }
+  }
+
   pub fn is_space(&self) -> bool {
     match self {
       &Token::Space => true,
@@ -168,70 +296,129 @@ impl Token {
   }
 }
 
+impl TokenKind {
+  /// Whether tokens of this kind have a payload.
+  pub fn has_payload(&self) -> bool {
+    self.bare().is_none()
+  }
+}
+
 thread_local! {
-  static TL_TRIE: Rc<ReTrie<Token>> = fresh_tokenizer_trie();
+  static TL_TRIE: Rc<ReTrie<Token>> = static_tokenizer_trie(LexerProfile::default());
 }
 
 pub fn tl_tokenizer_trie() -> Rc<ReTrie<Token>> {
   TL_TRIE.with(|trie| trie.clone())
 }
 
+#[cfg(feature = "regex")]
 pub fn fresh_tokenizer_trie() -> Rc<ReTrie<Token>> {
-  let mut tr = ReTrie::default();
+  let profile = LexerProfile::default();
+  fresh_tokenizer_trie2(profile, &profile.keyword_table())
+}
+
+/// Build the tokenizer trie for a profile, with the hard keywords of `kws`
+/// (usually `profile.keyword_table()`); soft keywords are lexed as
+/// identifiers (see `SoftKeywords`).
+#[cfg(feature = "regex")]
+pub fn fresh_tokenizer_trie2(profile: LexerProfile, kws: &KeywordTable) -> Rc<ReTrie<Token>> {
+  ReTrie::from_rules(tokenizer_rules(profile, kws)).into()
+}
+
+/// Load the tokenizer trie of a profile from the tables compiled ahead of
+/// time into `static_lexer`, which needs no regex parsing.
+pub fn static_tokenizer_trie(profile: LexerProfile) -> Rc<ReTrie<Token>> {
+  let rules = tokenizer_rules(profile, &profile.keyword_table());
+  ReTrie::from_static(profile.static_trie(), rules.into_map_funs()).into()
+}
+
+pub fn tokenizer_rules(profile: LexerProfile, kws: &KeywordTable) -> ReRules<Token> {
+  let custom = profile.has_custom_ops();
+  let mut tr = ReRules::default();
   tr.push(r"[ \t]+", |_| Token::Space);
   tr.push(r"\#",    |_| Token::CommentNL(SafeStr::default()));
+  // NB: all newline forms lex as `Token::NL`; the original form is
+  // recovered from the token span via `NewlineForm::of`.
+  tr.push(r"\r\n",  |_| Token::NL);
   tr.push(r"\n",    |_| Token::NL);
-  tr.push(r"\r",    |_| Token::CR);
+  tr.push(r"\r",    |_| Token::NL);
   tr.push(r"\\",    |_| Token::Backslash);
   tr.push(r",",     |_| Token::Comma);
+  tr.push(r"\.\.\.",  |_| Token::DotDotDot);
   tr.push(r"\.",    |_| Token::Dot);
-  //tr.push(r";;",    |_| Token::SemiSemi);
+  if custom {
+    tr.push(r";;",    |_| Token::SemiSemi);
+  }
   tr.push(r";",     |_| Token::Semi);
-  tr.push(r":\~",   |_| Token::LTilde);
-  tr.push(r":\-",   |_| Token::LDash);
-  tr.push(r"::",    |_| Token::ColonColon);
+  if custom {
+    tr.push(r":\~",   |_| Token::LTilde);
+    tr.push(r":\-",   |_| Token::LDash);
+    tr.push(r"::",    |_| Token::ColonColon);
+  }
+  tr.push(r":=",    |_| Token::ColonEq);
   tr.push(r":",     |_| Token::Colon);
   tr.push(r"\-=",   |_| Token::DashEq);
-  tr.push(r"\-:",   |_| Token::RDash);
+  if custom {
+    tr.push(r"\-:",   |_| Token::RDash);
+  }
   tr.push(r"\->",   |_| Token::RArrow);
   tr.push(r"\-",    |_| Token::Dash);
   tr.push(r"\+=",   |_| Token::PlusEq);
   //tr.push(r"\+\+",  |_| Token::PlusPlus);
   tr.push(r"\+",    |_| Token::Plus);
+  tr.push(r"\*\*=",  |_| Token::StarStarEq);
   tr.push(r"\*=",   |_| Token::StarEq);
   tr.push(r"\*\*",  |_| Token::StarStar);
   tr.push(r"\*",    |_| Token::Star);
+  tr.push(r"//=",   |_| Token::SlashSlashEq);
   tr.push(r"/=",    |_| Token::SlashEq);
   tr.push(r"//",    |_| Token::SlashSlash);
   tr.push(r"/",     |_| Token::Slash);
   tr.push(r"%=",    |_| Token::PercentEq);
   tr.push(r"%",     |_| Token::Percent);
-  tr.push(r"=>",    |_| Token::REqArrow);
+  if custom {
+    tr.push(r"=>",    |_| Token::REqArrow);
+  }
   tr.push(r"==",    |_| Token::EqEq);
   tr.push(r"=",     |_| Token::Equal);
   tr.push(r">>=",   |_| Token::RShiftEq);
   tr.push(r">>",    |_| Token::RShift);
   tr.push(r">=",    |_| Token::Geq);
   tr.push(r">",     |_| Token::Gt);
-  tr.push(r"<\~",   |_| Token::LTildeArrow);
-  tr.push(r"<\-",   |_| Token::LArrow);
+  if custom {
+    tr.push(r"<\~",   |_| Token::LTildeArrow);
+    tr.push(r"<\-",   |_| Token::LArrow);
+  }
   tr.push(r"<<=",   |_| Token::LShiftEq);
   tr.push(r"<<",    |_| Token::LShift);
   tr.push(r"<=",    |_| Token::Leq);
+  // NB: as in CPython's tokenizer, `<>` lexes as not-equal (PEP 401),
+  // leaving it to the parser to reject.
+  tr.push(r"<>",    |_| Token::Neq);
   tr.push(r"<",     |_| Token::Lt);
-  tr.push(r"\~>",   |_| Token::RTildeArrow);
-  tr.push(r"\~:",   |_| Token::RTilde);
+  if custom {
+    tr.push(r"\~>",   |_| Token::RTildeArrow);
+    tr.push(r"\~:",   |_| Token::RTilde);
+  }
+  tr.push(r"\&=",   |_| Token::AmpEq);
   tr.push(r"\&",    |_| Token::Amp);
+  tr.push(r"\|=",   |_| Token::BarEq);
   tr.push(r"\|",    |_| Token::Bar);
-  tr.push(r"\?\~",  |_| Token::LQueryTilde);
-  tr.push(r"\?\-",  |_| Token::LQueryDash);
-  tr.push(r"\?",    |_| Token::Query);
-  tr.push(r"!\~",   |_| Token::LBangTilde);
+  if custom {
+    tr.push(r"\?\~",  |_| Token::LQueryTilde);
+    tr.push(r"\?\-",  |_| Token::LQueryDash);
+    tr.push(r"\?",    |_| Token::Query);
+    tr.push(r"!\~",   |_| Token::LBangTilde);
+  }
   tr.push(r"!=",    |_| Token::Neq);
-  tr.push(r"!\-",   |_| Token::LBangDash);
-  tr.push(r"!",     |_| Token::Bang);
+  if custom {
+    tr.push(r"!\-",   |_| Token::LBangDash);
+    tr.push(r"!",     |_| Token::Bang);
+  }
+  tr.push(r"\^=",   |_| Token::CaretEq);
   tr.push(r"\^",    |_| Token::Caret);
   tr.push(r"\~",    |_| Token::Tilde);
+  tr.push(r"@=",    |_| Token::AtEq);
   tr.push(r"@",     |_| Token::At);
   tr.push(r"\(",    |_| Token::LParen);
   tr.push(r"\)",    |_| Token::RParen);
@@ -239,49 +426,17 @@ pub fn fresh_tokenizer_trie() -> Rc<ReTrie<Token>> {
   tr.push(r"\]",    |_| Token::RBrack);
   tr.push(r"\{",    |_| Token::LCurly);
   tr.push(r"\}",    |_| Token::RCurly);
-  tr.push(r"True",  |_| Token::True);
-  tr.push(r"False", |_| Token::False);
-  tr.push(r"None",  |_| Token::None);
-  tr.push(r"and",   |_| Token::And);
-  tr.push(r"async", |_| Token::Async);
-  tr.push(r"assert", |_| Token::Assert);
-  tr.push(r"as",    |_| Token::As);
-  tr.push(r"await", |_| Token::Await);
-  tr.push(r"break", |_| Token::Break);
-  tr.push(r"case",  |_| Token::Case);
-  tr.push(r"class", |_| Token::Class);
-  tr.push(r"continue", |_| Token::Continue);
-  tr.push(r"def",   |_| Token::Def);
-  tr.push(r"del",   |_| Token::Del);
-  tr.push(r"elif",  |_| Token::Elif);
-  tr.push(r"else",  |_| Token::Else);
-  tr.push(r"except", |_| Token::Except);
-  tr.push(r"finally", |_| Token::Finally);
-  tr.push(r"from",  |_| Token::From);
-  tr.push(r"for",   |_| Token::For);
-  tr.push(r"global", |_| Token::Global);
-  tr.push(r"import", |_| Token::Import);
-  tr.push(r"if",    |_| Token::If);
-  tr.push(r"in",    |_| Token::In);
-  tr.push(r"is",    |_| Token::Is);
-  tr.push(r"lambda", |_| Token::Lambda);
-  tr.push(r"match", |_| Token::Match);
-  tr.push(r"nonlocal", |_| Token::Nonlocal);
-  tr.push(r"not",   |_| Token::Not);
-  tr.push(r"or",    |_| Token::Or);
-  tr.push(r"pass",  |_| Token::Pass);
-  tr.push(r"raise", |_| Token::Raise);
-  tr.push(r"return", |_| Token::Return);
-  tr.push(r"try",   |_| Token::Try);
-  tr.push(r"type",  |_| Token::Type);
-  tr.push(r"where", |_| Token::Where);
-  tr.push(r"while", |_| Token::While);
-  tr.push(r"with",  |_| Token::With);
-  tr.push(r"yield", |_| Token::Yield);
-  tr.push(r"[0-9]+", |s| Token::Int(s.into()));
-  tr.push(r"_",     |_| Token::Place);
-  tr.push(r"[A-Za-z_][0-9A-Za-z_]*", |s| Token::Ident(s.into()));
-  tr.into()
+  for (kw, tok) in kws.hard() {
+    let tok = tok.clone();
+    tr.push(&re_escape(kw), move |_| tok.clone());
+  }
+  // NB: this also admits misplaced underscores (`1__0`, `1_`), which
+  // are left for the parser to reject.
+  tr.push(r"[0-9][0-9_]*", |s| Token::Int(s.into()));
+  // NB: identifiers per PEP 3131; `_` is in XID_Continue but not in
+  // XID_Start, so it is added to the initial class explicitly.
+  tr.push(r"[_\p{XID_Start}]\p{XID_Continue}*", |s| Token::Ident(nfkc_ident(s)));
+  tr
 }
 
 struct Buffer<S> {
@@ -295,7 +450,13 @@ impl<S: AsRef<str>> Buffer<S> {
   }
 
   pub fn peek_char(&self) -> Option<char> {
-    self.as_str().chars().next()
+    let s = self.as_str();
+    match s.as_bytes().first() {
+      None => None,
+      // NB: an ASCII char is its byte, with no decoding.
+      Some(&b) if b < 0x80 => Some(b as char),
+      Some(_) => s.chars().next()
+    }
   }
 
   pub fn peek_char2(&self) -> Option<char> {
@@ -321,10 +482,10 @@ impl<S: AsRef<str>> Buffer<S> {
 }
 
 pub struct Tokenizer<S> {
-  trie: Rc<ReTrie<Token>>,
-  bol:  bool,
-  eof:  Option<CharSpan>,
-  buf:  Buffer<S>,
+  trie:     Rc<ReTrie<Token>>,
+  bol:      bool,
+  eof:      Option<CharSpan>,
+  buf:      Buffer<S>,
 }
 
 impl<S> Tokenizer<S> {
@@ -335,143 +496,612 @@ impl<S> Tokenizer<S> {
   pub fn new2(trie: Rc<ReTrie<Token>>, s: S) -> Tokenizer<S> {
     Tokenizer{
       trie,
-      bol:  true,
-      eof:  None,
-      buf:  Buffer{str_: s, off: 0},
+      bol:      true,
+      eof:      None,
+      buf:      Buffer{str_: s, off: 0},
     }
   }
 }
 
-impl<S: AsRef<str>> Iterator for Tokenizer<S> {
-  type Item = (CharSpan, Token);
+/// A token as lexed by `Tokenizer::_next`, before its payload is built.
+enum RawToken {
+  Tok(Token),
+  /// A string literal, which has been checked by `scan_str`.
+  Lit,
+  /// A match of a rule of the tokenizer trie.
+  Rule(RuleId),
+}
 
-  fn next(&mut self) -> Option<(CharSpan, Token)> {
+impl<S: AsRef<str>> Tokenizer<S> {
+  fn _next(&mut self) -> Option<(CharSpan, RawToken)> {
     if let Some(end_span) = self.eof {
-      return Some((end_span, Token::_Eof));
+      return Some((end_span, RawToken::Tok(Token::_Eof)));
     }
-    let (mut span, mut tok) = {
-      let c = self.buf.peek_char()?;
-      if self.bol {
-        if c == ' ' || c == '\t' {
-          let mut indent = 0;
-          let mut o = 0;
-          for c in self.buf.as_str().chars() {
-            match c {
-              ' ' => {
-                indent += 1;
-                o += 1;
-              }
-              '\t' => {
-                indent = (indent / 8 + 1) * 8;
-                o += 1;
-              }
-              _ => break
+    let c = self.buf.peek_char()?;
+    if self.bol {
+      if c == ' ' || c == '\t' {
+        let mut indent = 0;
+        let mut o = 0;
+        for &b in self.buf.as_str().as_bytes().iter() {
+          match b {
+            b' ' => {
+              indent += 1;
+              o += 1;
+            }
+            b'\t' => {
+              indent = (indent / 8 + 1) * 8;
+              o += 1;
             }
+            _ => break
           }
+        }
+        let start = self.buf.position();
+        self.buf.advance(o);
+        let end = self.buf.position();
+        return Some((CharSpan{start, end}, RawToken::Tok(Token::IndentSpace(indent))));
+      }
+      self.bol = false;
+    }
+    /*if c == 'f' || c == 'r' {
+      let c2 = match self.buf.peek_char2() {
+        None => {
           let start = self.buf.position();
-          self.buf.advance(o);
+          self.buf.advance(1);
           let end = self.buf.position();
-          return Some((CharSpan{start, end}, Token::IndentSpace(indent)));
+          self.eof = Some(CharSpan{start: end, end});
+          return Some((CharSpan{start, end}, Token::Ident(format!("{}", c).into()));
         }
-        self.bol = false;
+        Some(c2) => c2
+      };
+      if c2 == '\'' || c2 == '\"' {
+        // FIXME
       }
-      /*if c == 'f' || c == 'r' {
-        let c2 = match self.buf.peek_char2() {
+    }*/
+    /*let mut doc = false;
+    if c == '\"' {
+      let c2 = match self.buf.peek_char2() {
+        None => {
+        }
+        Some(c2) => c2
+      };
+      if c2 == '\"' {
+        let c3 = match self.buf.peek_char3() {
           None => {
-            let start = self.buf.position();
-            self.buf.advance(1);
-            let end = self.buf.position();
-            self.eof = Some(CharSpan{start: end, end});
-            return Some((CharSpan{start, end}, Token::Ident(format!("{}", c).into()));
           }
-          Some(c2) => c2
+          Some(c3) => c3
         };
-        if c2 == '\'' || c2 == '\"' {
+        if c3 == '\"' {
+          doc = true;
           // FIXME
         }
-      }*/
-      /*let mut doc = false;
-      if c == '\"' {
-        let c2 = match self.buf.peek_char2() {
-          None => {
+      }
+    }*/
+    let start = self.buf.position();
+    if c == '\'' || (c == '\"' /*&& !doc*/) {
+      let o = match scan_str(self.buf.as_str(), c) {
+        Err(_) => {
+          //println!("DEBUG:  Tokenizer::next: eof 2");
+          return Some(self._eof());
+        }
+        Ok(o) => o
+      };
+      self.buf.advance(o);
+      let end = self.buf.position();
+      return Some((CharSpan{start, end}, RawToken::Lit));
+    }
+    let (rule, end) = match self.trie.rules().match_at(self.buf.str_.as_ref(), start) {
+      None => {
+        //println!("DEBUG:  Tokenizer::next: eof 3");
+        return Some(self._eof());
+      }
+      Some(m) => m
+    };
+    self.buf.advance(end - start);
+    Some((CharSpan{start, end}, RawToken::Rule(rule)))
+  }
+
+  fn _eof(&mut self) -> (CharSpan, RawToken) {
+    let end = self.buf.position();
+    self.eof = Some(CharSpan{start: end, end});
+    (CharSpan{start: end, end}, RawToken::Tok(Token::_Eof))
+  }
+
+  /// Skip the rest of a comment up to the line break, returning its span.
+  fn _comment(&mut self) -> CharSpan {
+    // NB: the line break bytes never occur inside a multibyte char, so
+    // the rest of the comment is found by a byte scan.
+    let rest = self.buf.as_str();
+    let o = match rest.bytes().position(|b| b == b'\n' || b == b'\r') {
+      // NB: comment could end on eof.
+      None => rest.len(),
+      Some(o) => {
+        self.bol = true;
+        o
+      }
+    };
+    let start = self.buf.position();
+    self.buf.advance(o);
+    let end = self.buf.position();
+    CharSpan{start, end}
+  }
+}
+
+impl<S: AsRef<str>> Iterator for Tokenizer<S> {
+  type Item = (CharSpan, Token);
+
+  fn next(&mut self) -> Option<(CharSpan, Token)> {
+    let (mut span, raw) = self._next()?;
+    let tok = match raw {
+      RawToken::Tok(tok) => tok,
+      RawToken::Lit => {
+        let text = &self.buf.str_.as_ref()[span.start .. span.end];
+        let (s, _) = unescape_str(text, text.chars().next().unwrap()).unwrap();
+        //println!("DEBUG:  Tokenizer::next: lit (unescaped): {}", safe_ascii(s.as_bytes()));
+        Token::Lit(s.into())
+      }
+      RawToken::Rule(rule) => {
+        let tok = self.trie.apply(rule, &self.buf.str_.as_ref()[span.start .. span.end]).unwrap();
+        match tok {
+          Token::NL => {
+            self.bol = true;
+            Token::NL
           }
-          Some(c2) => c2
-        };
-        if c2 == '\"' {
-          let c3 = match self.buf.peek_char3() {
-            None => {
-            }
-            Some(c3) => c3
-          };
-          if c3 == '\"' {
-            doc = true;
-            // FIXME
+          Token::CommentNL(_) => {
+            let s_span = self._comment();
+            span.end = s_span.end;
+            Token::CommentNL(self.buf.str_.as_ref()[s_span.start .. s_span.end].into())
           }
+          tok => tok
         }
-      }*/
-      if c == '\'' || (c == '\"' /*&& !doc*/) {
-        let (s, o) = match unescape_str(self.buf.as_str(), c) {
-          Err(_) => {
-            //println!("DEBUG:  Tokenizer::next: eof 2");
-            let end = self.buf.position();
-            self.eof = Some(CharSpan{start: end, end});
-            return Some((CharSpan{start: end, end}, Token::_Eof));
-          }
-          Ok(t) => t
-        };
-        //println!("DEBUG:  Tokenizer::next: lit (unescaped): {}", safe_ascii(s.as_bytes()));
-        let start = self.buf.position();
-        self.buf.advance(o);
-        let end = self.buf.position();
-        (CharSpan{start, end}, Token::Lit(s.into()))
-      } else {
-        let (next_tok, o) = match self.trie.match_(self.buf.as_str()) {
-          None => {
-            //println!("DEBUG:  Tokenizer::next: eof 3");
-            let end = self.buf.position();
-            self.eof = Some(CharSpan{start: end, end});
-            return Some((CharSpan{start: end, end}, Token::_Eof));
+      }
+    };
+    //println!("DEBUG:  Tokenizer::next: tok={:?} off={}", tok, self.off);
+    Some((span, tok))
+  }
+}
+
+/// A token whose payload borrows from the source text, as lexed by
+/// `RefTokenizer`. `to_token` converts it to the owned `Token`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum TokenRef<'src> {
+  /// A token with no payload to borrow, e.g. a keyword or operator.
+  Owned(Token),
+  /// The text of the comment after the `#`.
+  CommentNL(&'src str),
+  Int(&'src str),
+  /// The literal as written, with its quotes and escapes.
+  Lit(&'src str),
+  /// The identifier as written, before normalization.
+  Ident(&'src str),
+}
+
+impl<'src> TokenRef<'src> {
+  /// Convert to the owned `Token`, unescaping literals and normalizing
+  /// identifiers. Panics if a `Lit` is not a valid literal.
+  pub fn to_token(&self) -> Token {
+    match self {
+      &TokenRef::Owned(ref tok) => tok.clone(),
+      &TokenRef::CommentNL(s) => Token::CommentNL(s.into()),
+      &TokenRef::Int(s) => Token::Int(s.into()),
+      &TokenRef::Lit(s) => {
+        let (s, _) = unescape_str(s, s.chars().next().unwrap()).unwrap();
+        Token::Lit(s.into())
+      }
+      &TokenRef::Ident(s) => Token::Ident(nfkc_ident(s)),
+    }
+  }
+
+  /// The symbol in `intern` of the payload of an `Ident` or a `Lit`, as
+  /// normalized or unescaped by `to_token`.
+  pub fn intern<I: Intern + ?Sized>(&self, intern: &I) -> Option<Symbol> {
+    match self {
+      &TokenRef::Owned(Token::Ident(ref s)) |
+      &TokenRef::Owned(Token::Lit(ref s)) => Some(intern.intern(s.as_raw_str())),
+      // NB: ASCII identifiers are already normalized, and literals without
+      // escapes are the text between their quotes.
+      &TokenRef::Ident(s) if s.is_ascii() => Some(intern.intern(s)),
+      &TokenRef::Ident(s) => Some(intern.intern(nfkc_ident(s).as_raw_str())),
+      &TokenRef::Lit(s) if !s.contains('\\') => Some(intern.intern(&s[1 .. s.len() - 1])),
+      &TokenRef::Lit(s) => {
+        let (s, _) = unescape_str(s, s.chars().next().unwrap()).unwrap();
+        Some(intern.intern(&s))
+      }
+      _ => None
+    }
+  }
+}
+
+/// Tokenizer yielding `TokenRef`s, which borrow their payloads from the
+/// source text instead of allocating them.
+///
+/// The `Int` and `Ident` rules of the trie are taken to map their matched
+/// text to the payload, as in `tokenizer_rules`.
+pub struct RefTokenizer<'src> {
+  inner:    Tokenizer<&'src str>,
+  // The token of each rule of the trie, from its first match.
+  toks:     Vec<Option<Token>>,
+}
+
+impl<'src> RefTokenizer<'src> {
+  pub fn new(s: &'src str) -> RefTokenizer<'src> {
+    RefTokenizer::new2(tl_tokenizer_trie(), s)
+  }
+
+  pub fn new2(trie: Rc<ReTrie<Token>>, s: &'src str) -> RefTokenizer<'src> {
+    let toks = vec![None; trie.rules().len()];
+    RefTokenizer{
+      inner:    Tokenizer::new2(trie, s),
+      toks,
+    }
+  }
+
+  /// Yield the symbols of payloads in `intern` along with the tokens; see
+  /// `SymTokenizer`.
+  pub fn with_interner<I: Intern>(self, intern: I) -> SymTokenizer<'src, I> {
+    SymTokenizer{inner: self, intern}
+  }
+}
+
+impl<'src> Iterator for RefTokenizer<'src> {
+  type Item = (CharSpan, TokenRef<'src>);
+
+  fn next(&mut self) -> Option<(CharSpan, TokenRef<'src>)> {
+    let src = self.inner.buf.str_;
+    let (mut span, raw) = self.inner._next()?;
+    let text = &src[span.start .. span.end];
+    let tok = match raw {
+      RawToken::Tok(tok) => TokenRef::Owned(tok),
+      RawToken::Lit => TokenRef::Lit(text),
+      RawToken::Rule(rule) => {
+        if self.toks[rule.index()].is_none() {
+          self.toks[rule.index()] = Some(self.inner.trie.apply(rule, text).unwrap());
+        }
+        match self.toks[rule.index()].as_ref().unwrap() {
+          &Token::NL => {
+            self.inner.bol = true;
+            TokenRef::Owned(Token::NL)
           }
-          Some((next_tok, next_off)) => {
-            //println!("DEBUG:  Tokenizer::next: next tok={:?} off={}", next_tok, next_off);
-            (next_tok, next_off)
+          &Token::CommentNL(_) => {
+            let s_span = self.inner._comment();
+            span.end = s_span.end;
+            TokenRef::CommentNL(&src[s_span.start .. s_span.end])
           }
-        };
-        let start = self.buf.position();
-        self.buf.advance(o);
-        let end = self.buf.position();
-        (CharSpan{start, end}, next_tok)
+          &Token::Int(_) => TokenRef::Int(text),
+          &Token::Ident(_) => TokenRef::Ident(text),
+          &Token::Lit(_) => TokenRef::Owned(self.inner.trie.apply(rule, text).unwrap()),
+          tok => TokenRef::Owned(tok.clone())
+        }
       }
     };
-    match &mut tok {
-      // FIXME: CR.
-      &mut Token::NL => {
-        self.bol = true;
+    Some((span, tok))
+  }
+}
+
+/// Tokenizer yielding the tokens of a `RefTokenizer` with the symbols of
+/// their `Ident` and `Lit` payloads in an interner, which may be shared
+/// with other tokenizers, e.g. as an `Rc<Interner>` or an
+/// `Arc<SyncInterner>`.
+pub struct SymTokenizer<'src, I> {
+  inner:    RefTokenizer<'src>,
+  intern:   I,
+}
+
+impl<'src, I: Intern> SymTokenizer<'src, I> {
+  pub fn new(intern: I, s: &'src str) -> SymTokenizer<'src, I> {
+    RefTokenizer::new(s).with_interner(intern)
+  }
+
+  pub fn new2(trie: Rc<ReTrie<Token>>, intern: I, s: &'src str) -> SymTokenizer<'src, I> {
+    RefTokenizer::new2(trie, s).with_interner(intern)
+  }
+
+  pub fn interner(&self) -> &I {
+    &self.intern
+  }
+}
+
+impl<'src, I: Intern> Iterator for SymTokenizer<'src, I> {
+  type Item = (CharSpan, TokenRef<'src>, Option<Symbol>);
+
+  fn next(&mut self) -> Option<(CharSpan, TokenRef<'src>, Option<Symbol>)> {
+    let (span, tok) = self.inner.next()?;
+    let sym = tok.intern(&self.intern);
+    Some((span, tok, sym))
+  }
+}
+
+/// The tokens of a whole text, stored as a struct of arrays: the kind,
+/// span, and payload of token `i` are `kinds[i]`, `spans[i]`, and
+/// `payloads[i]`.
+///
+/// The payload is the width of an `IndentSpace`, or the symbol in the
+/// table's interner of the text of a `CommentNL`, `Int`, `Lit`, or
+/// `Ident`, and 0 for other kinds. An `_Eof` for an unlexable rest of the
+/// text is kept as the last token.
+pub struct TokenTable {
+  pub kinds:    Vec<TokenKind>,
+  pub spans:    Vec<CharSpan>,
+  pub payloads: Vec<u32>,
+  intern:       Box<dyn Intern>,
+}
+
+impl TokenTable {
+  pub fn new(s: &str) -> TokenTable {
+    TokenTable::new2(tl_tokenizer_trie(), Interner::new(), s)
+  }
+
+  /// Tokenize `s` in one pass with `trie`, interning payloads with
+  /// `intern`, which may be shared by the tables of several files, e.g. as
+  /// an `Rc<Interner>`.
+  pub fn new2<I: Intern + 'static>(trie: Rc<ReTrie<Token>>, intern: I, s: &str) -> TokenTable {
+    let mut kinds = Vec::new();
+    let mut spans = Vec::new();
+    let mut payloads = Vec::new();
+    for (span, tok) in RefTokenizer::new2(trie, s) {
+      let (kind, payload) = match tok {
+        TokenRef::CommentNL(s) => (TokenKind::CommentNL, intern.intern(s).0),
+        TokenRef::Int(s) => (TokenKind::Int, intern.intern(s).0),
+        TokenRef::Lit(_) => (TokenKind::Lit, tok.intern(&intern).unwrap().0),
+        TokenRef::Ident(_) => (TokenKind::Ident, tok.intern(&intern).unwrap().0),
+        TokenRef::Owned(tok) => TokenTable::_payload(&intern, &tok),
+      };
+      kinds.push(kind);
+      spans.push(span);
+      payloads.push(payload);
+      if kind == TokenKind::_Eof {
+        break;
       }
-      &mut Token::CommentNL(ref mut s_) => {
-        let mut s = String::new();
-        loop {
-          // NB: comment could end on eof.
-          let c = match self.buf.peek_char() {
-            None => {
-              break;
-            }
-            Some(c) => c
-          };
-          if c == '\n' {
-            self.bol = true;
-            break;
+    }
+    TokenTable{kinds, spans, payloads, intern: Box::new(intern)}
+  }
+
+  fn _payload<I: Intern>(intern: &I, tok: &Token) -> (TokenKind, u32) {
+    let payload = match tok {
+      &Token::IndentSpace(n) => n,
+      &Token::CommentNL(ref s) |
+      &Token::Int(ref s) |
+      &Token::Lit(ref s) |
+      &Token::Ident(ref s) => intern.intern(s.as_raw_str()).0,
+      _ => 0
+    };
+    (tok.kind(), payload)
+  }
+
+  pub fn len(&self) -> usize {
+    self.kinds.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.kinds.is_empty()
+  }
+
+  pub fn interner(&self) -> &dyn Intern {
+    &*self.intern
+  }
+
+  /// The symbol of the payload of token `i`, if it has one.
+  pub fn symbol(&self, i: usize) -> Option<Symbol> {
+    match self.kinds[i] {
+      TokenKind::CommentNL |
+      TokenKind::Int |
+      TokenKind::Lit |
+      TokenKind::Ident => Some(Symbol(self.payloads[i])),
+      _ => None
+    }
+  }
+
+  /// The owned token `i`.
+  pub fn token(&self, i: usize) -> Token {
+    self.kinds[i]._from_table(self.payloads[i], &*self.intern)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item=(CharSpan, Token)> + '_ {
+    (0 .. self.len()).map(move |i| (self.spans[i], self.token(i)))
+  }
+}
+
+/// Tokenize `s` with the tokenizer of `profile` on up to `nthreads`
+/// threads, giving the same tokens as `Tokenizer`, up to and including
+/// its first `_Eof`.
+///
+/// The text is cut into chunks at line starts outside of strings and
+/// brackets, each of which is tokenized on its own thread within the
+/// whole text, so that spans are absolute. A cut is only kept if the
+/// chunk before it ends on a line break token, where the tokenizer starts
+/// a fresh line (and its indentation) as it does at the start of the text;
+/// otherwise, the rest of the text is tokenized sequentially.
+pub fn tokenize_parallel(profile: LexerProfile, s: &str, nthreads: usize) -> Vec<(CharSpan, Token)> {
+  let bounds = _split_lines(s, nthreads);
+  if bounds.len() <= 2 {
+    return _tokenize_chunk(tl_profile_trie(profile), s, 0, s.len()).0;
+  }
+  let chunks: Vec<_> = scope(|sc| {
+    let handles: Vec<_> = bounds.windows(2).map(|w| {
+      let (start, end) = (w[0], w[1]);
+      sc.spawn(move || _tokenize_chunk(tl_profile_trie(profile), s, start, end))
+    }).collect();
+    handles.into_iter().map(|h| h.join().unwrap()).collect()
+  });
+  let mut toks = Vec::new();
+  for (&start, (chunk, ok)) in bounds.iter().zip(chunks.into_iter()) {
+    if !ok {
+      //println!("DEBUG:  tokenize_parallel: bad cut at {}", start);
+      let (rest, _) = _tokenize_chunk(tl_profile_trie(profile), s, start, s.len());
+      toks.extend(rest);
+      break;
+    }
+    let eof = chunk.last().map(|(_, tok)| tok.is_eof()).unwrap_or(false);
+    toks.extend(chunk);
+    if eof {
+      break;
+    }
+  }
+  toks
+}
+
+/// Tokenize `s[start .. end]`, which is taken to begin a line, within the
+/// whole text. Also returns whether the tokens end on a line break at
+/// `end`, or on an `_Eof`, so that a chunk may follow.
+fn _tokenize_chunk(trie: Rc<ReTrie<Token>>, s: &str, start: usize, end: usize) -> (Vec<(CharSpan, Token)>, bool) {
+  let mut toks = Tokenizer::new2(trie, s);
+  toks.buf.advance(start);
+  let mut chunk = Vec::new();
+  while toks.buf.position() < end {
+    let (span, tok) = match toks.next() {
+      None => break,
+      Some(t) => t
+    };
+    let eof = tok.is_eof();
+    chunk.push((span, tok));
+    if eof {
+      return (chunk, true);
+    }
+  }
+  let ok = toks.buf.position() == end && (end == s.len() || toks.bol);
+  (chunk, ok)
+}
+
+/// Cut `s` into up to `n` chunks of about the same length, returning the
+/// chunk bounds. Each cut is at a line start outside of brackets, strings,
+/// and backslash continuations.
+fn _split_lines(s: &str, n: usize) -> Vec<usize> {
+  let bytes = s.as_bytes();
+  let target = bytes.len() / n.max(1);
+  let mut bounds = vec![0];
+  let mut depth = 0;
+  let mut quote = None;
+  let mut escape = false;
+  let mut comment = false;
+  let mut prev = 0;
+  for (i, &b) in bytes.iter().enumerate() {
+    if bounds.len() >= n {
+      break;
+    }
+    if b == b'\n' {
+      let cont = prev == b'\\' || (prev == b'\r' && i >= 2 && bytes[i - 2] == b'\\');
+      if depth == 0 && !cont && i + 1 - bounds[bounds.len() - 1] >= target && i + 1 < bytes.len() {
+        bounds.push(i + 1);
+      }
+      // NB: strings and comments end at the line break.
+      quote = None;
+      escape = false;
+      comment = false;
+      prev = b;
+      continue;
+    }
+    prev = b;
+    if comment {
+      continue;
+    }
+    match quote {
+      Some(q) => {
+        if escape {
+          escape = false;
+        } else if b == b'\\' {
+          escape = true;
+        } else if b == q {
+          quote = None;
+        }
+      }
+      None => match b {
+        b'#' => comment = true,
+        b'\'' | b'\"' => quote = Some(b),
+        b'(' | b'[' | b'{' => depth += 1,
+        b')' | b']' | b'}' => if depth > 0 {
+          depth -= 1;
+        },
+        _ => {}
+      }
+    }
+  }
+  bounds.push(bytes.len());
+  bounds
+}
+
+/// Whether the rest of the text `rest`, at which the tokenizer failed,
+/// fails only because it is cut short, i.e. it is a string literal that
+/// runs unterminated to the end of the text.
+fn lex_cut_at_end(rest: &str) -> bool {
+  let mut bytes = rest.bytes();
+  let delim = match bytes.next() {
+    Some(b @ b'\'') | Some(b @ b'"') => b,
+    _ => return false
+  };
+  while let Some(b) = bytes.next() {
+    if b == delim || b <= 0x1f {
+      return false;
+    } else if b == b'\\' {
+      bytes.next();
+    }
+  }
+  true
+}
+
+/// Tokenizer over source bytes, which are decoded per `SourceText::decode`.
+/// Spans are byte offsets into the original input.
+///
+/// A decoding error is reported as a `Token::_Err` spanning the offending
+/// bytes, after the tokens of the valid prefix; then the tokenizer stops
+/// with `Token::_Eof`. If the valid prefix fails to lex before its end,
+/// the tokenizer stops there with `Token::_Eof`, as `Tokenizer` does.
+pub struct ByteTokenizer<'a> {
+  inner:    Tokenizer<SourceText<'a>>,
+  err:      Option<SourceError>,
+  eof:      Option<CharSpan>,
+}
+
+impl<'a> ByteTokenizer<'a> {
+  pub fn new(bytes: &'a [u8]) -> ByteTokenizer<'a> {
+    ByteTokenizer::new2(tl_tokenizer_trie(), bytes)
+  }
+
+  pub fn new2(trie: Rc<ReTrie<Token>>, bytes: &'a [u8]) -> ByteTokenizer<'a> {
+    let src = SourceText::decode(bytes);
+    let err = src.error();
+    ByteTokenizer{
+      inner:    Tokenizer::new2(trie, src),
+      err,
+      eof:      None,
+    }
+  }
+
+  pub fn source(&self) -> &SourceText<'a> {
+    &self.inner.buf.str_
+  }
+}
+
+impl<'a> Iterator for ByteTokenizer<'a> {
+  type Item = (CharSpan, Token);
+
+  fn next(&mut self) -> Option<(CharSpan, Token)> {
+    if let Some(end_span) = self.eof {
+      return Some((end_span, Token::_Eof));
+    }
+    match self.inner.next() {
+      None => {
+        let err = self.err.take()?;
+        self.eof = Some(CharSpan{start: err.span.end, end: err.span.end});
+        Some((err.span, Token::_Err))
+      }
+      Some((span, tok)) => {
+        if tok.is_eof() {
+          // NB: the valid prefix may end inside a token (e.g. a string
+          // literal), which then fails to lex; the decoding error is the
+          // cause, and is reported instead. A token that fails to lex
+          // before the end of the valid prefix is reported as is.
+          let rest = &self.inner.buf.str_.as_str()[span.start .. ];
+          if self.err.is_some() && lex_cut_at_end(rest) {
+            let err = self.err.take().unwrap();
+            self.eof = Some(CharSpan{start: err.span.end, end: err.span.end});
+            return Some((err.span, Token::_Err));
           }
-          let o = len_utf8(c as _);
-          s.push(c);
-          self.buf.advance(o);
         }
-        span.end = self.buf.position();
-        let _ = replace(s_, s.into());
+        let span = self.inner.buf.str_.orig_span(span);
+        if tok.is_eof() {
+          self.eof = Some(span);
+        }
+        Some((span, tok))
       }
-      _ => {}
     }
-    //println!("DEBUG:  Tokenizer::next: tok={:?} off={}", tok, self.off);
-    Some((span, tok))
   }
 }
@@ -0,0 +1,125 @@
+use crate::keyword::{KeywordClass, KeywordTable};
+#[cfg(feature = "regex")]
+use crate::parsing::{fresh_tokenizer_trie2};
+use crate::parsing::{Token, static_tokenizer_trie};
+#[cfg(feature = "regex")]
+use crate::re::{ReStaticWriter};
+use crate::re::{ReStaticTrie, ReTrie};
+use crate::static_lexer;
+
+use std::cell::{RefCell};
+use std::collections::{BTreeMap};
+use std::rc::{Rc};
+
+/// The language version targeted by the tokenizer, which determines the
+/// enabled keywords, operators, and literal forms.
+///
+/// `Clothespin` is the Python 3.12 token set extended with the custom
+/// keywords (`where`) and operators (`:-`, `<-`, `~>`, etc.) of our DSL.
+///
+/// All profiles have the operators and literal forms up to Python 3.8,
+/// e.g. `:=`, `@=`, `->`, and underscores in numbers (`1_000`).
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum LexerProfile {
+  Py38,
+  Py310,
+  Py312,
+  Clothespin,
+}
+
+impl Default for LexerProfile {
+  fn default() -> LexerProfile {
+    LexerProfile::Clothespin
+  }
+}
+
+impl LexerProfile {
+  /// The Python version whose token set this profile includes.
+  pub fn py_version(&self) -> (u8, u8) {
+    match self {
+      &LexerProfile::Py38 => (3, 8),
+      &LexerProfile::Py310 => (3, 10),
+      &LexerProfile::Py312 => (3, 12),
+      &LexerProfile::Clothespin => (3, 12),
+    }
+  }
+
+  /// The non-Python operators of the Clothespin DSL, e.g. `:-`, `::`,
+  /// `<-`, `~>`, `=>`, `?`, `!`.
+  ///
+  /// These must be disabled when lexing Python, where e.g. `a[:-1]` and
+  /// `x<-1` would otherwise lex `:-` and `<-` as single tokens.
+  pub fn has_custom_ops(&self) -> bool {
+    self == &LexerProfile::Clothespin
+  }
+
+  pub fn keyword_table(&self) -> KeywordTable {
+    let mut kws = KeywordTable::default();
+    if self != &LexerProfile::Clothespin {
+      kws.remove("where");
+    }
+    if self.py_version() < (3, 12) {
+      kws.remove("type");
+    }
+    if self.py_version() < (3, 10) {
+      kws.remove("match");
+      kws.remove("case");
+      kws.remove("_");
+    }
+    kws
+  }
+
+  /// The tokenizer trie of this profile, compiled into static tables.
+  pub fn static_trie(&self) -> &'static ReStaticTrie {
+    match self {
+      &LexerProfile::Py38 => &static_lexer::PY38,
+      &LexerProfile::Py310 => &static_lexer::PY310,
+      &LexerProfile::Py312 => &static_lexer::PY312,
+      &LexerProfile::Clothespin => &static_lexer::CLOTHESPIN,
+    }
+  }
+
+  /// Whether `kw` is a keyword of this profile, and its class.
+  pub fn keyword_class(&self, kw: &str) -> Option<KeywordClass> {
+    self.keyword_table().get(kw).map(|(_, class)| class)
+  }
+}
+
+thread_local! {
+  static TL_PROFILE_TRIES: RefCell<BTreeMap<LexerProfile, Rc<ReTrie<Token>>>> = RefCell::new(BTreeMap::new());
+}
+
+/// The (thread-local, lazily loaded) tokenizer trie of a profile.
+pub fn tl_profile_trie(profile: LexerProfile) -> Rc<ReTrie<Token>> {
+  TL_PROFILE_TRIES.with(|tries| {
+    tries.borrow_mut().entry(profile).or_insert_with(|| {
+      static_tokenizer_trie(profile)
+    }).clone()
+  })
+}
+
+pub const PROFILES: &'static [(LexerProfile, &'static str)] = &[
+  (LexerProfile::Py38,        "PY38"),
+  (LexerProfile::Py310,       "PY310"),
+  (LexerProfile::Py312,       "PY312"),
+  (LexerProfile::Clothespin,  "CLOTHESPIN"),
+];
+
+/// Generate the source of `static_lexer`, by building the tokenizer trie
+/// of each profile from its rules.
+///
+/// Run `make gen` after changing `tokenizer_rules`, the keyword tables, or
+/// the trie representation.
+#[cfg(feature = "regex")]
+pub fn gen_static_lexer() -> String {
+  let mut sw = ReStaticWriter::new();
+  for &(profile, name) in PROFILES.iter() {
+    let trie = fresh_tokenizer_trie2(profile, &profile.keyword_table());
+    sw.write_trie(name, trie.rules()).unwrap();
+  }
+  let mut src = String::new();
+  src.push_str("// Generated by `examples/gen_static_lexer.rs`; do not edit.\n");
+  src.push_str("// See `profile::gen_static_lexer`.\n\n");
+  sw.finish(&mut src).unwrap();
+  src
+}
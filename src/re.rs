@@ -1,7 +1,20 @@
-use regex_syntax::{Parser as ReParser};
-use regex_syntax::hir::{HirKind as ReExpKind, Hir as ReExp, Class as ReClass};
+use crate::parsing::{CharSpan};
 
+#[cfg(feature = "regex")]
+use regex_syntax::{Error as ReError, Parser as ReParser};
+#[cfg(feature = "regex")]
+use regex_syntax::ast::{Ast as ReAst, RepetitionKind as ReAstRepKind};
+#[cfg(feature = "regex")]
+use regex_syntax::ast::parse::{Parser as ReAstParser};
+#[cfg(feature = "regex")]
+use regex_syntax::hir::{HirKind as ReExpKind, Hir as ReExp, Class as ReClass, Look as ReLook};
+use smol_str::{SmolStr};
+
+use std::cell::{OnceCell};
+use std::cmp::{Ordering};
 use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Write as FmtWrite, Result as FmtResult};
+use std::rc::{Rc};
 use std::str::{from_utf8};
 
 // `len_utf8` below is from rust libcore (Apache-2.0/MIT).
@@ -23,6 +36,24 @@ pub fn len_utf8(code: u32) -> usize {
   }
 }
 
+/// Test whether `c` lies within one of `ranges`, which must be sorted and
+/// disjoint (as are the ranges of a `regex_syntax` class).
+///
+/// This is a binary search, so large Unicode classes (e.g. `XID_Continue`,
+/// with several hundred ranges) are cheap to test per char.
+#[inline]
+pub fn ranges_contain(ranges: &[(char, char)], c: char) -> bool {
+  ranges.binary_search_by(|&(lb, ub)| {
+    if ub < c {
+      Ordering::Less
+    } else if c < lb {
+      Ordering::Greater
+    } else {
+      Ordering::Equal
+    }
+  }).is_ok()
+}
+
 pub struct ReMultiMapValues<'a, K, V> {
   x:    Option<V>,
   k:    K,
@@ -50,7 +81,7 @@ impl<'a, K: Copy + Ord, V: Copy + Ord> Iterator for ReMultiMapValues<'a, K, V> {
   }
 }
 
-#[derive(Default)]
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
 pub struct ReMultiMap<K: Copy + Ord, V: Copy + Ord> {
   rt:   BTreeMap<K, (V, V)>,
   ch:   BTreeMap<(K, V), V>,
@@ -91,593 +122,3241 @@ impl<K: Copy + Ord, V: Copy + Ord> ReMultiMap<K, V> {
   }
 }
 
-pub struct ReTrie<T> {
-  inner:    ReInnerTrie,
-  map_funs: Vec<Box<dyn Fn(&str) -> T>>,
+/// A map from char ranges to values, which answers which of the values
+/// have a range containing a given char.
+///
+/// The ranges are cut at all of their bounds into disjoint segments, each
+/// listing the values of the ranges that cover it, so that a lookup is a
+/// binary search over the segments. The segments are built on the first
+/// lookup after an insert.
+#[derive(Clone, Debug)]
+pub struct ReRangeMap<V: Copy> {
+  entries:  Vec<((char, char), V)>,
+  index:    OnceCell<ReRangeIndex<V>>,
 }
 
-impl<T> Default for ReTrie<T> {
-  fn default() -> ReTrie<T> {
-    ReTrie{
-      inner:    ReInnerTrie::default(),
-      map_funs: Vec::new(),
-    }
+#[derive(Clone, Debug)]
+struct ReRangeIndex<V> {
+  // The first char of each segment; a segment extends up to the next one.
+  starts:   Vec<u32>,
+  // The values of segment `i` are `values[offsets[i] .. offsets[i + 1]]`.
+  offsets:  Vec<usize>,
+  values:   Vec<V>,
+}
+
+impl<V: Copy> Default for ReRangeMap<V> {
+  fn default() -> ReRangeMap<V> {
+    ReRangeMap{entries: Vec::new(), index: OnceCell::new()}
   }
 }
 
-thread_local! {
-  static TL_RE_PARSER: ReParser = ReParser::new();
+// NB: `index` is a cache, and does not take part.
+impl<V: Copy + PartialEq> PartialEq for ReRangeMap<V> {
+  fn eq(&self, other: &ReRangeMap<V>) -> bool {
+    self.entries == other.entries
+  }
 }
 
-impl<T> ReTrie<T> {
-  pub fn push<'s, F: 'static + Fn(&str) -> T>(&mut self, rstr: &'s str, map_fun: F) {
-    // FIXME: regex-syntax: parser not properly reset.
-    let rexp = match TL_RE_PARSER.with(|parser| parser.parse(rstr)) {
-    //let rexp = match ReParser::new().parse(rstr) {}
-      Err(_) => panic!("bug: ReTrie::push: regexp parse failure: {:?}", rstr),
-      Ok(rexp) => rexp
+impl<V: Copy + Eq> Eq for ReRangeMap<V> {}
+
+impl<V: Copy + PartialEq> ReRangeMap<V> {
+  pub fn insert(&mut self, k: (char, char), v: V) {
+    self.entries.push((k, v));
+    self.index = OnceCell::new();
+  }
+
+  pub fn contains_value(&self, v: V) -> bool {
+    self.entries.iter().any(|&(_, x)| x == v)
+  }
+
+  /// The values whose range contains `c`, in insertion order.
+  pub fn values_at(&self, c: char) -> &[V] {
+    let index = self.index.get_or_init(|| ReRangeIndex::new(&self.entries));
+    let i = match index.starts.binary_search(&(c as u32)) {
+      Ok(i) => i,
+      Err(0) => return &[],
+      Err(i) => i - 1
     };
-    let rkind = rexp.kind();
-    let ridx = self.map_funs.len();
-    match rkind {
-      &ReExpKind::Concat(ref rexps) => {
-        //println!("DEBUG: re: push: Concat: ridx={} rexps.len={}", ridx, rexps.len());
-        assert!(!rexps.is_empty());
-        let (rexp, rexps) = rexps.split_first().unwrap();
-        self.inner._push(rexp, rexps, ridx);
-      }
-      &ReExpKind::Literal(_) => {
-        self.inner._push(&rexp, &[], ridx);
-      }
-      &ReExpKind::Class(_) => {
-        self.inner._push(&rexp, &[], ridx);
+    &index.values[index.offsets[i] .. index.offsets[i + 1]]
+  }
+}
+
+impl<V: Copy> ReRangeIndex<V> {
+  fn new(entries: &[((char, char), V)]) -> ReRangeIndex<V> {
+    let mut starts = Vec::with_capacity(2 * entries.len());
+    for &((lb, ub), _) in entries.iter() {
+      starts.push(lb as u32);
+      starts.push(ub as u32 + 1);
+    }
+    starts.sort_unstable();
+    starts.dedup();
+    let mut segs: Vec<Vec<V>> = vec![Vec::new(); starts.len()];
+    for &((lb, ub), v) in entries.iter() {
+      let i = starts.binary_search(&(lb as u32)).unwrap();
+      let j = starts.binary_search(&(ub as u32 + 1)).unwrap();
+      for seg in segs[i .. j].iter_mut() {
+        seg.push(v);
       }
-      &ReExpKind::Repetition(_) => {
-        self.inner._push(&rexp, &[], ridx);
+    }
+    let mut offsets = Vec::with_capacity(starts.len() + 1);
+    let mut values = Vec::new();
+    offsets.push(0);
+    for seg in segs.into_iter() {
+      values.extend(seg);
+      offsets.push(values.len());
+    }
+    ReRangeIndex{starts, offsets, values}
+  }
+}
+
+/// Escape the regex meta characters of `s`, so that it matches literally.
+pub fn re_escape(s: &str) -> String {
+  let mut buf = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '\\' | '.' | '+' | '*' | '?' | '(' | ')' | '|' | '[' | ']' |
+      '{' | '}' | '^' | '$' | '#' | '&' | '-' | '~' => {
+        buf.push('\\');
       }
-      _ => panic!("bug: ReTrie::push: unimplemented ({:?}): {:?}", rkind, rstr)
+      _ => {}
     }
-    self.map_funs.push(Box::new(map_fun));
+    buf.push(c);
   }
+  buf
+}
 
-  pub fn match_at<'t>(&self, text: &'t str, pos: usize) -> Option<(T, usize)> {
-    let query = match text.get(pos .. ) {
-      None => panic!("bug"),
-      Some(s) => s
-    };
-    self.match_(query).map(|(v, off)| (v, pos + off))
+pub type ReMapFun<T> = Box<dyn Fn(&str) -> T>;
+pub type ReMatchFun<T> = Box<dyn Fn(&ReMatch) -> T>;
+
+enum ReFun<T> {
+  Str(Rc<dyn Fn(&str) -> T>),
+  Match(Rc<dyn Fn(&ReMatch) -> T>),
+}
+
+impl<T> Clone for ReFun<T> {
+  fn clone(&self) -> ReFun<T> {
+    match self {
+      &ReFun::Str(ref f) => ReFun::Str(f.clone()),
+      &ReFun::Match(ref f) => ReFun::Match(f.clone()),
+    }
   }
+}
 
-  pub fn match_<'t>(&self, query: &'t str) -> Option<(T, usize)> {
-    //println!("TRACE: re: match_: pos={} query={}", pos, query);
-    let (mat_len, ridx) = self.inner._match(query, None)?;
-    //println!("TRACE: re: match_:   ridx={} mat.len={}", ridx, mat_len);
-    let (tok, _) = query.split_at(mat_len);
-    Some((self.map_funs[ridx](tok), mat_len))
+/// A match of a rule, with the spans of its capture groups; see
+/// `ReRuleTrie::captures` and `ReTrie::push_match`.
+///
+/// Group 0 is the whole match; the other groups are numbered by their
+/// opening parens, as in `regex`. Spans are relative to the start of the
+/// match.
+pub struct ReMatch<'a> {
+  text:     &'a str,
+  slots:    Vec<Option<usize>>,
+  names:    &'a [(usize, SmolStr)],
+}
+
+impl<'a> ReMatch<'a> {
+  pub fn as_str(&self) -> &'a str {
+    self.text
   }
 
-  pub fn match_split<'t>(&self, query: &'t str) -> Option<(T, &'t str)> {
-    let (mat_len, ridx) = self.inner._match(query, None)?;
-    let (tok, rem) = query.split_at(mat_len);
-    Some((self.map_funs[ridx](tok), rem))
+  /// The number of groups, including group 0.
+  pub fn ngroups(&self) -> usize {
+    self.slots.len() / 2
+  }
+
+  pub fn span(&self, group: usize) -> Option<CharSpan> {
+    let start = (*self.slots.get(2 * group)?)?;
+    let end = (*self.slots.get(2 * group + 1)?)?;
+    Some(CharSpan{start, end})
+  }
+
+  pub fn get(&self, group: usize) -> Option<&'a str> {
+    let span = self.span(group)?;
+    Some(&self.text[span.start .. span.end])
+  }
+
+  pub fn name_span(&self, name: &str) -> Option<CharSpan> {
+    let &(group, _) = self.names.iter().find(|&&(_, ref n)| n == name)?;
+    self.span(group)
+  }
+
+  pub fn name(&self, name: &str) -> Option<&'a str> {
+    let span = self.name_span(name)?;
+    Some(&self.text[span.start .. span.end])
   }
 }
 
-enum ReInnerTrieRef {
-  Terminal(usize),
-  Trie(ReInnerTrie),
+/// Rules, in priority order, from which a `ReTrie` is built: either by
+/// parsing the patterns (`ReTrie::from_rules`), or by pairing the map
+/// functions with a prebuilt trie (`ReTrie::from_static`).
+pub struct ReRules<T> {
+  rules:    Vec<(SmolStr, ReMapFun<T>)>,
 }
 
-#[derive(Default)]
-struct ReInnerTrie {
-  index:    Option<usize>,
-  zero:     BTreeSet<usize>,
-  lit_term: ReMultiMap<char, usize>,
-  literal:  BTreeMap<char, ReInnerTrie>,
-  klass:    ReMultiMap<(char, char), usize>,
-  bound1:   ReMultiMap<(char, char), usize>,
-  unbound:  ReMultiMap<(char, char), usize>,
-  ranges:   BTreeMap<usize, Vec<(char, char)>>,
-  non_lit:  Vec<ReInnerTrieRef>,
+impl<T> Default for ReRules<T> {
+  fn default() -> ReRules<T> {
+    ReRules{rules: Vec::new()}
+  }
 }
 
-impl ReInnerTrie {
-  fn new(index: usize) -> ReInnerTrie {
-    let mut trie = ReInnerTrie::default();
-    trie.index = Some(index);
-    trie
+impl<T> ReRules<T> {
+  pub fn push<'s, F: 'static + Fn(&str) -> T>(&mut self, rstr: &'s str, map_fun: F) {
+    self.rules.push((rstr.into(), Box::new(map_fun)));
   }
 
-  fn _push(&mut self, rexp: &ReExp, rexps: &[ReExp], ridx: usize) {
-    if let Some(idx) = self.index {
-      if idx != ridx {
-        self.index = None;
-      }
+  pub fn len(&self) -> usize {
+    self.rules.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.rules.is_empty()
+  }
+
+  pub fn patterns<'a>(&'a self) -> impl Iterator<Item=&'a str> + 'a {
+    self.rules.iter().map(|r| r.0.as_str())
+  }
+
+  pub fn into_map_funs(self) -> Vec<ReMapFun<T>> {
+    self.rules.into_iter().map(|r| r.1).collect()
+  }
+}
+
+/// The id of a rule of a `ReRuleTrie`. Rules are numbered from 0 in the
+/// order in which they were pushed, which is also their priority order.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct RuleId(pub usize);
+
+impl RuleId {
+  pub fn index(self) -> usize {
+    self.0
+  }
+}
+
+/// A set of rules matched by a trie of their patterns, identified by their
+/// `RuleId`.
+///
+/// At each position, the rule with the longest match wins, and ties go to
+/// the earlier rule. Within a rule, greedy repetitions (`*`, `+`, `?`)
+/// take as many chars as they can, without backtracking, so `".*"` never
+/// matches. Lazy repetitions (`*?`, `+?`, `??`) take as few chars as they
+/// need for the rest of the rule to match, so `"/\*.*?\*/"` matches up to
+/// the first `*/`; the length of that match is then compared with the
+/// matches of the other rules as usual.
+///
+/// Capture groups do not affect matching; their spans are found after the
+/// fact, for the winning rule only (see `captures`).
+#[derive(Clone, Default, Debug)]
+pub struct ReRuleTrie {
+  inner:    ReInnerTrie,
+  nrules:   usize,
+  // The names of the capture groups of each rule, if any.
+  names:    BTreeMap<usize, Vec<(usize, SmolStr)>>,
+  // The pattern of each rule as a sequence of steps, recovered from the
+  // trie when first needed.
+  paths:    OnceCell<Vec<Vec<ReStep>>>,
+  // The chars that may start a match, for `find`.
+  first:    OnceCell<ReFirst>,
+}
+
+// NB: `paths` and `first` are caches, and do not take part.
+impl PartialEq for ReRuleTrie {
+  fn eq(&self, other: &ReRuleTrie) -> bool {
+    self.nrules == other.nrules && self.names == other.names && self.inner == other.inner
+  }
+}
+
+impl Eq for ReRuleTrie {}
+
+impl ReRuleTrie {
+  pub fn new() -> ReRuleTrie {
+    ReRuleTrie::default()
+  }
+
+  /// Load a trie compiled into static tables; see `ReStaticWriter`.
+  pub fn from_static(tab: &ReStaticTrie) -> ReRuleTrie {
+    let mut names: BTreeMap<usize, Vec<(usize, SmolStr)>> = BTreeMap::new();
+    for &(ridx, group, name) in tab.names.iter() {
+      names.entry(ridx).or_default().push((group, name.into()));
     }
-    match rexp.kind() {
-      &ReExpKind::Literal(ref re_lit) => {
-        let lit_str = from_utf8(&*(re_lit.0)).unwrap();
-        let mut lit_chars = lit_str.chars();
-        let c = match lit_chars.next() {
-          None => panic!("bug"),
-          Some(c) => c
-        };
-        //println!("DEBUG: re: _push: Literal:   first c={}", c);
-        let mut new_rexps: Vec<ReExp> = Vec::new();
-        for next_c in lit_chars {
-          //println!("DEBUG: re: _push: Literal:   next c={}", next_c);
-          let buf: Vec<u8> = format!("{}", next_c).into();
-          new_rexps.push(ReExp::literal(buf.into_boxed_slice()));
-        }
-        new_rexps.extend_from_slice(rexps);
-        let rexps = new_rexps;
-        if rexps.is_empty() {
-          self.lit_term.insert(c, ridx);
-        } else {
-          if !self.literal.contains_key(&c) {
-            self.literal.insert(c, ReInnerTrie::new(ridx));
-          }
-          let (rexp, rexps) = rexps.split_first().unwrap();
-          self.literal.get_mut(&c).unwrap()._push(rexp, rexps, ridx);
-        }
-      }
-      &ReExpKind::Class(ref re_klass) => {
-        //println!("DEBUG: re: _push: Class: ridx={}", ridx);
-        match re_klass {
-          &ReClass::Unicode(ref klass) => {
-            let eidx = self.non_lit.len();
-            let mut ranges = Vec::new();
-            for range in klass.ranges() {
-              let lb = range.start();
-              let ub = range.end();
-              self.klass.insert((lb, ub), eidx);
-              ranges.push((lb, ub));
-            }
-            ranges.sort();
-            self.ranges.insert(eidx, ranges);
-            if rexps.is_empty() {
-              self.non_lit.push(ReInnerTrieRef::Terminal(ridx));
-            } else {
-              self.non_lit.push(ReInnerTrieRef::Trie(ReInnerTrie::new(ridx)));
-              let (rexp, rexps) = rexps.split_first().unwrap();
-              match &mut self.non_lit[eidx] {
-                &mut ReInnerTrieRef::Terminal(_) => unreachable!(),
-                &mut ReInnerTrieRef::Trie(ref mut trie) => {
-                  trie._push(rexp, rexps, ridx);
-                }
-              }
-            }
+    ReRuleTrie{
+      inner:    ReInnerTrie::from_static(tab.nodes, 0),
+      nrules:   tab.nrules,
+      names,
+      ..ReRuleTrie::default()
+    }
+  }
+
+  /// The number of rules.
+  pub fn len(&self) -> usize {
+    self.nrules
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.nrules == 0
+  }
+
+  /// Add a rule, panicking if its pattern is not supported; see `try_push`.
+  #[cfg(feature = "regex")]
+  pub fn push(&mut self, rstr: &str) -> RuleId {
+    match self.try_push(rstr) {
+      Err(e) => panic!("bug: ReRuleTrie::push: {:?}: {:?}", e, rstr),
+      Ok(rule) => rule
+    }
+  }
+
+  /// Add a rule, or return why its pattern is not supported, in which case
+  /// the trie is left unchanged.
+  #[cfg(feature = "regex")]
+  pub fn try_push(&mut self, rstr: &str) -> Result<RuleId, ReTrieError> {
+    let (rstr, ahead) = match split_lookahead(rstr) {
+      None => (rstr, None),
+      Some((rstr, negate, astr)) => (rstr, Some((negate, astr)))
+    };
+    // NB: only a trailing lookahead is supported.
+    if let Some(&span) = re_lookaheads(rstr).first() {
+      return Err(ReTrieError::Unsupported{kind: "Lookahead", span});
+    }
+    let rexp = re_parse(rstr, 0)?;
+    if let Err(kind) = re_check(&rexp) {
+      return Err(ReTrieError::Unsupported{kind, span: re_unsupported_span(rstr, kind)});
+    }
+    if re_nullable(&rexp) {
+      return Err(ReTrieError::EmptyMatch);
+    }
+    let ahead = match ahead {
+      None => None,
+      Some((negate, astr)) => {
+        let assert = if negate { ReAssert::NotAhead } else { ReAssert::Ahead };
+        let base = rstr.len() + 3;
+        // NB: the lookahead is parsed under the flags in effect at the end
+        // of the pattern (e.g. `(?i)`).
+        let flags = re_trailing_flags(rstr);
+        let ranges = match re_class_ranges(&re_parse(&format!("{}{}", flags, astr), base - flags.len())?) {
+          None => {
+            let span = CharSpan{start: base, end: base + astr.len()};
+            return Err(ReTrieError::Unsupported{kind: "Lookahead", span});
           }
-          _ => unimplemented!()
-        }
-      }
-      &ReExpKind::Repetition(ref re_rep) => {
-        //println!("DEBUG: re: _push: Repetition: ridx={}", ridx);
-        assert!(re_rep.greedy);
-        let (zero, unbound) = match (re_rep.min, re_rep.max) {
-          (0, Some(1)) => (true, false),
-          (0, None) => (true, true),
-          (1, None) => (false, true),
-          _ => unimplemented!()
+          Some(ranges) => ranges
         };
-        match re_rep.sub.kind() {
-          &ReExpKind::Literal(ref re_lit) => {
-            let lit_str = from_utf8(&*(re_lit.0)).unwrap();
-            let mut lit_chars = lit_str.chars();
-            let c = match lit_chars.next() {
-              None => panic!("bug"),
-              Some(c) => c
-            };
-            let eidx = self.non_lit.len();
-            if zero {
-              self.zero.insert(eidx);
-            }
-            let mut ranges = Vec::new();
-            if unbound {
-              self.unbound.insert((c, c), eidx);
-            } else {
-              self.bound1.insert((c, c), eidx);
-            }
-            ranges.push((c, c));
-            ranges.sort();
-            self.ranges.insert(eidx, ranges);
-            if rexps.is_empty() {
-              self.non_lit.push(ReInnerTrieRef::Terminal(ridx));
-            } else {
-              self.non_lit.push(ReInnerTrieRef::Trie(ReInnerTrie::new(ridx)));
-              let (rexp, rexps) = rexps.split_first().unwrap();
-              match &mut self.non_lit[eidx] {
-                &mut ReInnerTrieRef::Terminal(_) => unreachable!(),
-                &mut ReInnerTrieRef::Trie(ref mut trie) => {
-                  trie._push(rexp, rexps, ridx);
-                }
-              }
-            }
-          }
-          &ReExpKind::Class(ref re_klass) => match re_klass {
-            &ReClass::Unicode(ref klass) => {
-              let eidx = self.non_lit.len();
-              if zero {
-                self.zero.insert(eidx);
-              }
-              let mut ranges = Vec::new();
-              for range in klass.ranges() {
-                let lb = range.start();
-                let ub = range.end();
-                if unbound {
-                  self.unbound.insert((lb, ub), eidx);
-                } else {
-                  self.bound1.insert((lb, ub), eidx);
-                }
-                ranges.push((lb, ub));
-              }
-              ranges.sort();
-              self.ranges.insert(eidx, ranges);
-              if rexps.is_empty() {
-                self.non_lit.push(ReInnerTrieRef::Terminal(ridx));
-              } else {
-                self.non_lit.push(ReInnerTrieRef::Trie(ReInnerTrie::new(ridx)));
-                let (rexp, rexps) = rexps.split_first().unwrap();
-                match &mut self.non_lit[eidx] {
-                  &mut ReInnerTrieRef::Terminal(_) => unreachable!(),
-                  &mut ReInnerTrieRef::Trie(ref mut trie) => {
-                    trie._push(rexp, rexps, ridx);
-                  }
-                }
-              }
-            }
-            _ => unimplemented!()
-          }
-          _ => unimplemented!()
-        }
+        Some((assert, ranges))
       }
-      _ => unimplemented!()
+    };
+    let ridx = self.nrules;
+    let mut atoms = Vec::new();
+    let mut names = Vec::new();
+    re_atoms(&rexp, &mut atoms, &mut names);
+    if let Some((assert, ranges)) = ahead {
+      atoms.push(ReAtom::Assert(assert, ranges));
     }
+    let (atom, atoms) = atoms.split_first().unwrap();
+    self.inner._push(atom, atoms, ridx);
+    if !names.is_empty() {
+      self.names.insert(ridx, names);
+    }
+    self.nrules += 1;
+    self.paths = OnceCell::new();
+    self.first = OnceCell::new();
+    Ok(RuleId(ridx))
   }
 
-  fn _match(&self, query: &str, ctx: Option<usize>) -> Option<(usize, usize)> {
-    //println!("TRACE: re: _match: ctx={:?} idx={:?} query={}", ctx, self.index, query);
-    match (ctx, self.index) {
-      (Some(ctx), Some(idx)) => if ctx != idx {
-        //println!("TRACE: re: ctx: {:?} depth: {}, idx: {:?} mismatch", ctx, depth, self.index);
-        return None;
-      },
-      _ => {}
+  /// Match at `pos` in `text`, returning the winning rule and the end
+  /// position of the match.
+  ///
+  /// Unlike `match_`, the text before `pos` is taken into account by
+  /// assertions (e.g. `\b` and `(?m)^`).
+  pub fn match_at(&self, text: &str, pos: usize) -> Option<(RuleId, usize)> {
+    let query = match text.get(pos .. ) {
+      None => panic!("bug"),
+      Some(s) => s
+    };
+    let prev = text[ .. pos].chars().next_back();
+    let (mat_len, ridx) = self.inner._match(query, None, prev)?;
+    Some((RuleId(ridx), pos + mat_len))
+  }
+
+  /// Match at the start of `query`, which is taken to be the start of the
+  /// text, returning the winning rule and the length of the match.
+  pub fn match_(&self, query: &str) -> Option<(RuleId, usize)> {
+    let (mat_len, ridx) = self.inner._match(query, None, None)?;
+    Some((RuleId(ridx), mat_len))
+  }
+
+  /// Match every rule at `pos` in `text`, returning each rule that matches
+  /// and the end position of its match, in priority order: longest first,
+  /// then by rule id. The first entry, if any, is the match of `match_at`.
+  ///
+  /// Each rule is matched as if it were the only one, as by the walk of
+  /// the trie of `match_at` restricted to the rule; all of the rules are
+  /// matched in a single walk.
+  pub fn match_all_at(&self, text: &str, pos: usize) -> Vec<(RuleId, usize)> {
+    let query = match text.get(pos .. ) {
+      None => panic!("bug"),
+      Some(s) => s
+    };
+    let prev = text[ .. pos].chars().next_back();
+    self._match_all(query, prev).into_iter()
+      .map(|(rule, mat_len)| (rule, pos + mat_len))
+      .collect()
+  }
+
+  /// Match every rule at the start of `query`, returning each rule that
+  /// matches and the length of its match; see `match_all_at`.
+  pub fn match_all(&self, query: &str) -> Vec<(RuleId, usize)> {
+    self._match_all(query, None)
+  }
+
+  fn _match_all(&self, query: &str, prev: Option<char>) -> Vec<(RuleId, usize)> {
+    let mut mats: Vec<_> = self.inner._match_each(query, prev).into_iter()
+      .map(|(ridx, mat_len)| (RuleId(ridx), mat_len))
+      .collect();
+    mats.sort_by(|&(lrule, llen), &(rrule, rlen)| rlen.cmp(&llen).then(lrule.cmp(&rrule)));
+    mats
+  }
+
+  /// Find the capture group spans of `tok`, a match of `rule`, or return
+  /// `None` if `rule` is not a rule of the trie or `tok` is not a match of
+  /// it.
+  ///
+  /// The trie only tells which rule matched, and how much; the spans come
+  /// from matching `tok` against the steps of the rule again, backtracking
+  /// as needed, with repetitions preferring as many (or for lazy ones, as
+  /// few) chars as they can.
+  pub fn captures<'a>(&'a self, rule: RuleId, tok: &'a str) -> Option<ReMatch<'a>> {
+    let ridx = rule.index();
+    let path = self._paths().get(ridx)?;
+    let nslots = path.iter().filter_map(|step| match step {
+      &ReStep::Mark(slot) => Some(slot + 1),
+      _ => None
+    }).fold(2, |n, m| n.max(m));
+    let mut slots = vec![None; nslots];
+    slots[0] = Some(0);
+    slots[1] = Some(tok.len());
+    if !re_steps_captures(path, tok, 0, &mut slots) {
+      return None;
     }
-    let ctx = ctx.or(self.index);
-    let mut chars = query.char_indices();
-    let c = match chars.next() {
-      None => {
-        assert_eq!(query.len(), 0);
-        //println!("TRACE: re: ctx: {:?} depth: {} idx: {:?} empty suffix", top_ctx, depth, self.index);
-        let mut mat = None;
-        for &eidx in self.zero.iter() {
-          match &self.non_lit[eidx] {
-            &ReInnerTrieRef::Terminal(ridx) => match (ctx, mat) {
-              (None, None) => {
-                //println!("TRACE: re:   empty: terminal N N");
-                mat = Some((0, ridx));
-              }
-              (None, Some((_, old_idx))) => if ridx < old_idx {
-                //println!("TRACE: re:   empty: terminal N S");
-                mat = Some((0, ridx));
-              }
-              (Some(ctx), _) => if ctx == ridx {
-                //println!("TRACE: re:   empty: terminal S _");
-                mat = Some((0, ridx));
-                break;
-              }
-            }
-            &ReInnerTrieRef::Trie(ref trie) => match trie._match(query, ctx) {
-              None => {
-                //println!("TRACE: re:   empty: no trie match");
-              }
-              Some((_, ridx)) => match (ctx, mat) {
-                (None, None) => {
-                  //println!("TRACE: re:   empty: terminal N N");
-                  mat = Some((0, ridx));
-                }
-                (None, Some((_, old_idx))) => if ridx < old_idx {
-                  //println!("TRACE: re:   empty: terminal N S");
-                  mat = Some((0, ridx));
-                }
-                (Some(ctx), _) => if ctx == ridx {
-                  //println!("TRACE: re:   empty: terminal S _");
-                  mat = Some((0, ridx));
-                  break;
-                }
-              }
-            }
-          }
+    Some(ReMatch{
+      text:     tok,
+      slots,
+      names:    self.names.get(&ridx).map(|names| &names[ .. ]).unwrap_or(&[]),
+    })
+  }
+
+  fn _paths(&self) -> &[Vec<ReStep>] {
+    self.paths.get_or_init(|| {
+      let mut paths = vec![None; self.nrules];
+      self.inner._collect_paths(&mut Vec::new(), &mut paths);
+      paths.into_iter().map(|p| p.unwrap_or_default()).collect()
+    })
+  }
+
+  /// Find the leftmost match of any rule in `text`; see `find_at`.
+  pub fn find(&self, text: &str) -> Option<(RuleId, CharSpan)> {
+    self.find_at(text, 0)
+  }
+
+  /// Find the leftmost match of any rule in `text` starting at or after
+  /// `pos`. At the leftmost position where any rule matches, the match is
+  /// that of `match_at`.
+  ///
+  /// Positions whose char cannot start a match of any rule (e.g. in
+  /// between the literal prefixes of the rules) are skipped without
+  /// walking the trie.
+  pub fn find_at(&self, text: &str, pos: usize) -> Option<(RuleId, CharSpan)> {
+    let query = match text.get(pos .. ) {
+      None => panic!("bug"),
+      Some(s) => s
+    };
+    let first = self.first.get_or_init(|| self.inner._first());
+    let mut prev = text[ .. pos].chars().next_back();
+    for (off, c) in query.char_indices() {
+      if first.contains(c) {
+        if let Some((mat_len, ridx)) = self.inner._match(&query[off .. ], None, prev) {
+          return Some((RuleId(ridx), CharSpan{start: pos + off, end: pos + off + mat_len}));
         }
-        return mat;
       }
-      Some((pos, c)) => {
-        assert_eq!(pos, 0);
-        c
+      prev = Some(c);
+    }
+    if first.empty {
+      if let Some((_, ridx)) = self.inner._match("", None, prev) {
+        return Some((RuleId(ridx), CharSpan{start: text.len(), end: text.len()}));
       }
+    }
+    None
+  }
+
+  /// Iterate over the successive non-overlapping matches of the rules in
+  /// `text`, as found by `find_at`.
+  pub fn find_iter<'a, 't>(&'a self, text: &'t str) -> ReRuleFindIter<'a, 't> {
+    ReRuleFindIter{trie: self, text, pos: Some(0)}
+  }
+}
+
+pub struct ReRuleFindIter<'a, 't> {
+  trie:     &'a ReRuleTrie,
+  text:     &'t str,
+  pos:      Option<usize>,
+}
+
+impl<'a, 't> Iterator for ReRuleFindIter<'a, 't> {
+  type Item = (RuleId, CharSpan);
+
+  fn next(&mut self) -> Option<(RuleId, CharSpan)> {
+    let pos = self.pos?;
+    let (rule, span) = match self.trie.find_at(self.text, pos) {
+      None => {
+        self.pos = None;
+        return None;
+      }
+      Some(m) => m
     };
-    let c_len = len_utf8(c as _);
-    let next_pos = match chars.next() {
-      None => query.len(),
-      Some((pos, _)) => pos
-    };
-    drop(chars);
-    let suffix = match query.get(next_pos .. ) {
-      None => unreachable!(),
-      Some(s) => s
+    // NB: step past an empty match, so as to make progress.
+    self.pos = if span.end > span.start {
+      Some(span.end)
+    } else {
+      self.text[span.end .. ].chars().next().map(|c| span.end + c.len_utf8())
     };
-    //println!("TRACE: re: ctx: {:?} depth: {} idx: {:?} char: '{}' suffix: '{}'", top_ctx, depth, self.index, c, suffix);
-    let mut mat = None;
-    // FIXME: think about this.
-    let mut zero_mat = None;
-    for &eidx in self.zero.iter() {
-      match &self.non_lit[eidx] {
-        &ReInnerTrieRef::Terminal(ridx) => match (ctx, zero_mat) {
-          ////println!("TRACE: re:   repeat0: terminal ({})", ridx);
-          (None, None) => {
-            //println!("TRACE: re:   repeat0: terminal N N ({})", ridx);
-            zero_mat = Some((0, ridx));
-          }
-          (None, Some((old_len, old_idx))) => if ridx < old_idx {
-            //println!("TRACE: re:   repeat0: terminal N S ({})", ridx);
-            assert_eq!(old_len, 0);
-            zero_mat = Some((0, ridx));
-          }
-          (Some(ctx), _) => if ctx == ridx {
-            //println!("TRACE: re:   repeat0: terminal S _ ({})", ridx);
-            zero_mat = Some((0, ridx));
-            break;
-          }
-        }
-        &ReInnerTrieRef::Trie(ref trie) => match trie._match(query, ctx) {
-          None => {
-            //println!("TRACE: re:   repeat0: no trie/term match");
-          }
-          Some((0, ridx)) => match (ctx, zero_mat) {
-            ////println!("TRACE: re:   repeat0: trie/term ({})", ridx);
-            (None, None) => {
-              //println!("TRACE: re:   repeat0: trie/term N N ({})", ridx);
-              zero_mat = Some((0, ridx));
-            }
-            (None, Some((old_len, old_idx))) => if ridx < old_idx {
-              //println!("TRACE: re:   repeat0: trie/term N S ({})", ridx);
-              assert_eq!(old_len, 0);
-              zero_mat = Some((0, ridx));
+    Some((rule, span))
+  }
+}
+
+/// A `ReRuleTrie` together with a map function per rule, which turns the
+/// matched text of a rule into a value.
+///
+/// Matching is that of the underlying `ReRuleTrie`; rules are given by
+/// their index, i.e. `RuleId::index`.
+pub struct ReTrie<T> {
+  rules:    ReRuleTrie,
+  map_funs: Vec<ReFun<T>>,
+}
+
+impl<T> Default for ReTrie<T> {
+  fn default() -> ReTrie<T> {
+    ReTrie{
+      rules:    ReRuleTrie::default(),
+      map_funs: Vec::new(),
+    }
+  }
+}
+
+impl<T> ReTrie<T> {
+  #[cfg(feature = "regex")]
+  pub fn from_rules(rules: ReRules<T>) -> ReTrie<T> {
+    let mut trie = ReTrie::default();
+    for (rstr, map_fun) in rules.rules.into_iter() {
+      trie.rules.push(&rstr);
+      trie.map_funs.push(ReFun::Str(map_fun.into()));
+    }
+    trie
+  }
+
+  /// Pair a trie compiled into static tables (see `ReStaticWriter`) with
+  /// the map functions of the rules it was compiled from.
+  pub fn from_static(tab: &ReStaticTrie, map_funs: Vec<ReMapFun<T>>) -> ReTrie<T> {
+    assert_eq!(tab.nrules, map_funs.len(), "bug: ReTrie::from_static: rule count mismatch");
+    ReTrie{
+      rules:    ReRuleTrie::from_static(tab),
+      map_funs: map_funs.into_iter().map(|f| ReFun::Str(f.into())).collect(),
+    }
+  }
+
+  /// The underlying trie, for matching by `RuleId`.
+  pub fn rules(&self) -> &ReRuleTrie {
+    &self.rules
+  }
+
+  /// Add a rule, panicking if its pattern is not supported; see `try_push`.
+  #[cfg(feature = "regex")]
+  pub fn push<'s, F: 'static + Fn(&str) -> T>(&mut self, rstr: &'s str, map_fun: F) {
+    self.rules.push(rstr);
+    self.map_funs.push(ReFun::Str(Rc::new(map_fun)));
+  }
+
+  /// Add a rule, or return why its pattern is not supported, in which case
+  /// the trie is left unchanged.
+  #[cfg(feature = "regex")]
+  pub fn try_push<'s, F: 'static + Fn(&str) -> T>(&mut self, rstr: &'s str, map_fun: F) -> Result<(), ReTrieError> {
+    self.rules.try_push(rstr)?;
+    self.map_funs.push(ReFun::Str(Rc::new(map_fun)));
+    Ok(())
+  }
+
+  /// Add a rule whose map function is passed the match with the spans of
+  /// its capture groups, e.g. `0x(?P<digits>[0-9a-f]+)`.
+  ///
+  /// Panics if the pattern is not supported; see `try_push_match`.
+  #[cfg(feature = "regex")]
+  pub fn push_match<'s, F: 'static + Fn(&ReMatch) -> T>(&mut self, rstr: &'s str, map_fun: F) {
+    self.rules.push(rstr);
+    self.map_funs.push(ReFun::Match(Rc::new(map_fun)));
+  }
+
+  #[cfg(feature = "regex")]
+  pub fn try_push_match<'s, F: 'static + Fn(&ReMatch) -> T>(&mut self, rstr: &'s str, map_fun: F) -> Result<(), ReTrieError> {
+    self.rules.try_push(rstr)?;
+    self.map_funs.push(ReFun::Match(Rc::new(map_fun)));
+    Ok(())
+  }
+
+  /// Match at `pos` in `text`, returning the end position of the match.
+  ///
+  /// Unlike `match_`, the text before `pos` is taken into account by
+  /// assertions (e.g. `\b` and `(?m)^`).
+  pub fn match_at<'t>(&self, text: &'t str, pos: usize) -> Option<(T, usize)> {
+    let (rule, end) = self.rules.match_at(text, pos)?;
+    Some((self._apply(rule, &text[pos .. end]), end))
+  }
+
+  /// Match at the start of `query`, which is taken to be the start of the
+  /// text.
+  pub fn match_<'t>(&self, query: &'t str) -> Option<(T, usize)> {
+    let (rule, mat_len) = self.rules.match_(query)?;
+    let (tok, _) = query.split_at(mat_len);
+    Some((self._apply(rule, tok), mat_len))
+  }
+
+  pub fn match_split<'t>(&self, query: &'t str) -> Option<(T, &'t str)> {
+    let (rule, mat_len) = self.rules.match_(query)?;
+    let (tok, rem) = query.split_at(mat_len);
+    Some((self._apply(rule, tok), rem))
+  }
+
+  /// Match every rule at `pos` in `text`, returning the rule index, value,
+  /// and end position of each rule that matches; see
+  /// `ReRuleTrie::match_all_at`.
+  pub fn match_all_at<'t>(&self, text: &'t str, pos: usize) -> Vec<(usize, T, usize)> {
+    self.rules.match_all_at(text, pos).into_iter()
+      .map(|(rule, end)| (rule.index(), self._apply(rule, &text[pos .. end]), end))
+      .collect()
+  }
+
+  /// Match every rule at the start of `query`, returning the rule index,
+  /// value, and match length of each rule that matches; see `match_all_at`.
+  pub fn match_all<'t>(&self, query: &'t str) -> Vec<(usize, T, usize)> {
+    self.rules.match_all(query).into_iter()
+      .map(|(rule, mat_len)| (rule.index(), self._apply(rule, &query[ .. mat_len]), mat_len))
+      .collect()
+  }
+
+  /// Map `tok`, the text of a match of `rule`, to its value, or return
+  /// `None` if `rule` is not a rule of the trie, or its map function takes
+  /// the captures (see `push_match`) and `tok` is not a match of it. Other
+  /// rules are not matched again, and are passed `tok` as is.
+  pub fn apply(&self, rule: RuleId, tok: &str) -> Option<T> {
+    match self.map_funs.get(rule.index())? {
+      &ReFun::Str(ref map_fun) => Some(map_fun(tok)),
+      &ReFun::Match(ref map_fun) => Some(map_fun(&self.rules.captures(rule, tok)?)),
+    }
+  }
+
+  /// Map `tok`, a match of `rule` found by the trie, to its value.
+  fn _apply(&self, rule: RuleId, tok: &str) -> T {
+    match &self.map_funs[rule.index()] {
+      &ReFun::Str(ref map_fun) => map_fun(tok),
+      &ReFun::Match(ref map_fun) => match self.rules.captures(rule, tok) {
+        None => panic!("bug: ReTrie: no captures for rule {}: {:?}", rule.index(), tok),
+        Some(m) => map_fun(&m)
+      }
+    }
+  }
+
+  /// Find the leftmost match of any rule in `text`, returning the rule
+  /// index, value, and span of the match; see `ReRuleTrie::find_at`.
+  pub fn find<'t>(&self, text: &'t str) -> Option<(usize, T, CharSpan)> {
+    self.find_at(text, 0)
+  }
+
+  pub fn find_at<'t>(&self, text: &'t str, pos: usize) -> Option<(usize, T, CharSpan)> {
+    let (rule, span) = self.rules.find_at(text, pos)?;
+    Some((rule.index(), self._apply(rule, &text[span.start .. span.end]), span))
+  }
+
+  pub fn find_iter<'a, 't>(&'a self, text: &'t str) -> ReFindIter<'a, 't, T> {
+    ReFindIter{trie: self, iter: self.rules.find_iter(text)}
+  }
+}
+
+pub struct ReFindIter<'a, 't, T> {
+  trie:     &'a ReTrie<T>,
+  iter:     ReRuleFindIter<'a, 't>,
+}
+
+impl<'a, 't, T> Iterator for ReFindIter<'a, 't, T> {
+  type Item = (usize, T, CharSpan);
+
+  fn next(&mut self) -> Option<(usize, T, CharSpan)> {
+    let (rule, span) = self.iter.next()?;
+    let tok = &self.iter.text[span.start .. span.end];
+    Some((rule.index(), self.trie._apply(rule, tok), span))
+  }
+}
+
+/// The chars that may start a match of a trie. If `empty`, some rule may
+/// match without consuming a char, so that no position can be skipped.
+#[derive(Clone, Default, Debug)]
+struct ReFirst {
+  ascii:    u128,
+  ranges:   Vec<(char, char)>,
+  empty:    bool,
+}
+
+impl ReFirst {
+  fn insert(&mut self, lb: char, ub: char) {
+    for x in (lb as u32) .. (ub as u32).min(0x7f) + 1 {
+      self.ascii |= 1 << x;
+    }
+    if ub as u32 > 0x7f {
+      self.ranges.push((lb, ub));
+    }
+  }
+
+  fn contains(&self, c: char) -> bool {
+    if self.empty {
+      true
+    } else if (c as u32) < 0x80 {
+      self.ascii & (1 << (c as u32)) != 0
+    } else {
+      ranges_contain(&self.ranges, c)
+    }
+  }
+}
+
+/// A zero-width assertion of a pattern.
+///
+/// Assertions are checked against the chars before and after the match
+/// position, and against the ranges of their edge: the word chars for the
+/// word boundary assertions, and the lookahead class for `Ahead` and
+/// `NotAhead`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReAssert {
+  /// `\A`, or `^` outside of multi-line mode.
+  Start,
+  /// `\z`, or `$` outside of multi-line mode.
+  End,
+  /// `(?m)^`: the start of the text, or after a `\n`, `\r`, or `\r\n`.
+  LineStart,
+  /// `(?m)$`: the end of the text, or before a `\n`, `\r`, or `\r\n`.
+  LineEnd,
+  /// `\b`
+  WordBoundary,
+  /// `\B`
+  NotWordBoundary,
+  /// `\b{start}`, `\<`
+  WordStart,
+  /// `\b{end}`, `\>`
+  WordEnd,
+  /// `\b{start-half}`
+  WordStartHalf,
+  /// `\b{end-half}`
+  WordEndHalf,
+  /// `(?=[...])` at the end of a pattern.
+  Ahead,
+  /// `(?![...])` at the end of a pattern.
+  NotAhead,
+}
+
+/// All assertions, in the order of their codes in trie images.
+pub const RE_ASSERTS: &'static [ReAssert] = &[
+  ReAssert::Start,
+  ReAssert::End,
+  ReAssert::LineStart,
+  ReAssert::LineEnd,
+  ReAssert::WordBoundary,
+  ReAssert::NotWordBoundary,
+  ReAssert::WordStart,
+  ReAssert::WordEnd,
+  ReAssert::WordStartHalf,
+  ReAssert::WordEndHalf,
+  ReAssert::Ahead,
+  ReAssert::NotAhead,
+];
+
+impl ReAssert {
+  pub fn holds(&self, ranges: &[(char, char)], prev: Option<char>, next: Option<char>) -> bool {
+    let in_ranges = |c: Option<char>| c.map(|c| ranges_contain(ranges, c)).unwrap_or(false);
+    match self {
+      &ReAssert::Start => prev.is_none(),
+      &ReAssert::End => next.is_none(),
+      &ReAssert::LineStart => match prev {
+        None | Some('\n') => true,
+        // NB: not between the chars of a `\r\n`.
+        Some('\r') => next != Some('\n'),
+        _ => false
+      },
+      &ReAssert::LineEnd => match next {
+        None | Some('\r') => true,
+        Some('\n') => prev != Some('\r'),
+        _ => false
+      },
+      &ReAssert::WordBoundary => in_ranges(prev) != in_ranges(next),
+      &ReAssert::NotWordBoundary => in_ranges(prev) == in_ranges(next),
+      &ReAssert::WordStart => !in_ranges(prev) && in_ranges(next),
+      &ReAssert::WordEnd => in_ranges(prev) && !in_ranges(next),
+      &ReAssert::WordStartHalf => !in_ranges(prev),
+      &ReAssert::WordEndHalf => !in_ranges(next),
+      &ReAssert::Ahead => in_ranges(next),
+      &ReAssert::NotAhead => !in_ranges(next),
+    }
+  }
+}
+
+/// One step of a pattern, as pushed into a trie.
+#[cfg(feature = "regex")]
+enum ReAtom {
+  Char(char),
+  Class(Vec<(char, char)>),
+  Rep{ranges: Vec<(char, char)>, zero: bool, unbound: bool, lazy: bool},
+  Assert(ReAssert, Vec<(char, char)>),
+  /// The start (`2 * group`) or end (`2 * group + 1`) of a capture group.
+  Mark(usize),
+}
+
+/// Flatten a pattern accepted by `re_check` into atoms, collecting the
+/// names of its capture groups.
+#[cfg(feature = "regex")]
+fn re_atoms(rexp: &ReExp, atoms: &mut Vec<ReAtom>, names: &mut Vec<(usize, SmolStr)>) {
+  match rexp.kind() {
+    &ReExpKind::Literal(ref re_lit) => {
+      atoms.extend(from_utf8(&*(re_lit.0)).unwrap().chars().map(ReAtom::Char));
+    }
+    &ReExpKind::Class(ref re_klass) => {
+      atoms.push(ReAtom::Class(re_klass_ranges(re_klass)));
+    }
+    &ReExpKind::Look(look) => {
+      let (assert, ranges) = re_look_assert(look);
+      atoms.push(ReAtom::Assert(assert, ranges));
+    }
+    &ReExpKind::Repetition(ref re_rep) => {
+      let (zero, unbound) = match (re_rep.min, re_rep.max) {
+        (0, Some(1)) => (true, false),
+        (0, None) => (true, true),
+        (1, None) => (false, true),
+        _ => unreachable!()
+      };
+      let ranges = re_class_ranges(&re_rep.sub).unwrap();
+      atoms.push(ReAtom::Rep{ranges, zero, unbound, lazy: !re_rep.greedy});
+    }
+    &ReExpKind::Capture(ref re_cap) => {
+      let group = re_cap.index as usize;
+      if let Some(ref name) = re_cap.name {
+        names.push((group, (&**name).into()));
+      }
+      atoms.push(ReAtom::Mark(2 * group));
+      re_atoms(&re_cap.sub, atoms, names);
+      atoms.push(ReAtom::Mark(2 * group + 1));
+    }
+    &ReExpKind::Concat(ref rexps) => {
+      for rexp in rexps.iter() {
+        re_atoms(rexp, atoms, names);
+      }
+    }
+    &ReExpKind::Empty |
+    &ReExpKind::Alternation(_) => unreachable!()
+  }
+}
+
+#[cfg(feature = "regex")]
+fn re_look_assert(look: ReLook) -> (ReAssert, Vec<(char, char)>) {
+  let (assert, unicode) = match look {
+    ReLook::Start => (ReAssert::Start, false),
+    ReLook::End => (ReAssert::End, false),
+    ReLook::StartLF | ReLook::StartCRLF => (ReAssert::LineStart, false),
+    ReLook::EndLF | ReLook::EndCRLF => (ReAssert::LineEnd, false),
+    ReLook::WordAscii => (ReAssert::WordBoundary, false),
+    ReLook::WordAsciiNegate => (ReAssert::NotWordBoundary, false),
+    ReLook::WordUnicode => (ReAssert::WordBoundary, true),
+    ReLook::WordUnicodeNegate => (ReAssert::NotWordBoundary, true),
+    ReLook::WordStartAscii => (ReAssert::WordStart, false),
+    ReLook::WordEndAscii => (ReAssert::WordEnd, false),
+    ReLook::WordStartUnicode => (ReAssert::WordStart, true),
+    ReLook::WordEndUnicode => (ReAssert::WordEnd, true),
+    ReLook::WordStartHalfAscii => (ReAssert::WordStartHalf, false),
+    ReLook::WordEndHalfAscii => (ReAssert::WordEndHalf, false),
+    ReLook::WordStartHalfUnicode => (ReAssert::WordStartHalf, true),
+    ReLook::WordEndHalfUnicode => (ReAssert::WordEndHalf, true),
+  };
+  let ranges = match assert {
+    ReAssert::Start | ReAssert::End | ReAssert::LineStart | ReAssert::LineEnd => Vec::new(),
+    _ => if unicode {
+      re_class_ranges(&re_parse(r"\w", 0).unwrap()).unwrap()
+    } else {
+      vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')]
+    }
+  };
+  (assert, ranges)
+}
+
+/// The ranges of a pattern consisting of a single char or char class.
+#[cfg(feature = "regex")]
+fn re_class_ranges(rexp: &ReExp) -> Option<Vec<(char, char)>> {
+  match rexp.kind() {
+    &ReExpKind::Literal(ref re_lit) => {
+      let lit_str = from_utf8(&*(re_lit.0)).ok()?;
+      let mut lit_chars = lit_str.chars();
+      let c = lit_chars.next()?;
+      if lit_chars.next().is_some() {
+        return None;
+      }
+      Some(vec![(c, c)])
+    }
+    &ReExpKind::Class(ref re_klass) => Some(re_klass_ranges(re_klass)),
+    _ => None
+  }
+}
+
+/// The sorted char ranges of a class.
+///
+/// Case-insensitive (`(?i)`) literals and classes are already expanded to
+/// classes of all case variants by the parser. Byte classes (`(?-u)`) are
+/// taken as classes of the chars with the same code points; since the trie
+/// matches `str`s, the parser only admits byte classes of ASCII bytes.
+#[cfg(feature = "regex")]
+fn re_klass_ranges(re_klass: &ReClass) -> Vec<(char, char)> {
+  let mut ranges: Vec<_> = match re_klass {
+    &ReClass::Unicode(ref klass) => {
+      klass.ranges().iter().map(|r| (r.start(), r.end())).collect()
+    }
+    &ReClass::Bytes(ref klass) => {
+      klass.ranges().iter().map(|r| (r.start() as char, r.end() as char)).collect()
+    }
+  };
+  ranges.sort();
+  ranges
+}
+
+#[derive(Clone, Debug)]
+pub enum ReTrieError {
+  /// The pattern is not a valid regex.
+  Syntax{msg: String, span: CharSpan},
+  /// The pattern uses a construct that the trie does not support, given by
+  /// its HIR kind (e.g. `Alternation`), with the span of that construct in
+  /// the pattern (or of the whole pattern, if it could not be located).
+  Unsupported{kind: &'static str, span: CharSpan},
+  /// The pattern matches the empty string, so that a lexer using it could
+  /// make no progress.
+  EmptyMatch,
+}
+
+/// Parse a pattern, which starts at offset `base` of the original pattern.
+#[cfg(feature = "regex")]
+fn re_parse(rstr: &str, base: usize) -> Result<ReExp, ReTrieError> {
+  // NB: a parser is not reset between patterns, so that e.g. the flags
+  // of `(?m)` would carry over into later patterns; use a fresh one.
+  match ReParser::new().parse(rstr) {
+    Ok(rexp) => Ok(rexp),
+    Err(e) => {
+      let (msg, span) = match &e {
+        &ReError::Parse(ref e) => (e.kind().to_string(), *e.span()),
+        &ReError::Translate(ref e) => (e.kind().to_string(), *e.span()),
+        _ => {
+          let span = CharSpan{start: base, end: base + rstr.len()};
+          return Err(ReTrieError::Syntax{msg: e.to_string(), span});
+        }
+      };
+      let span = CharSpan{start: base + span.start.offset, end: base + span.end.offset};
+      Err(ReTrieError::Syntax{msg, span})
+    }
+  }
+}
+
+/// Check that a pattern is supported by `ReInnerTrie::_push`, or return
+/// the kind of the first unsupported construct.
+#[cfg(feature = "regex")]
+fn re_check(rexp: &ReExp) -> Result<(), &'static str> {
+  match rexp.kind() {
+    &ReExpKind::Concat(ref rexps) => {
+      for rexp in rexps.iter() {
+        re_check(rexp)?;
+      }
+      Ok(())
+    }
+    &ReExpKind::Capture(ref re_cap) => re_check(&re_cap.sub),
+    _ => re_check_atom(rexp)
+  }
+}
+
+#[cfg(feature = "regex")]
+fn re_check_atom(rexp: &ReExp) -> Result<(), &'static str> {
+  match rexp.kind() {
+    &ReExpKind::Literal(ref re_lit) => {
+      from_utf8(&*(re_lit.0)).map_err(|_| "Literal")?;
+      Ok(())
+    }
+    &ReExpKind::Class(_) |
+    &ReExpKind::Look(_) => Ok(()),
+    &ReExpKind::Repetition(ref re_rep) => {
+      match (re_rep.min, re_rep.max) {
+        (0, Some(1)) | (0, None) | (1, None) => {}
+        _ => return Err("Repetition")
+      }
+      match re_class_ranges(&re_rep.sub) {
+        None => Err("Repetition"),
+        Some(_) => Ok(())
+      }
+    }
+    &ReExpKind::Empty => Err("Empty"),
+    &ReExpKind::Alternation(_) => Err("Alternation"),
+    &ReExpKind::Capture(_) |
+    &ReExpKind::Concat(_) => unreachable!(),
+  }
+}
+
+#[cfg(feature = "regex")]
+fn re_nullable(rexp: &ReExp) -> bool {
+  match rexp.kind() {
+    &ReExpKind::Empty |
+    &ReExpKind::Look(_) => true,
+    &ReExpKind::Literal(_) |
+    &ReExpKind::Class(_) => false,
+    &ReExpKind::Repetition(ref re_rep) => re_rep.min == 0 || re_nullable(&re_rep.sub),
+    &ReExpKind::Capture(ref re_cap) => re_nullable(&re_cap.sub),
+    &ReExpKind::Concat(ref rexps) => rexps.iter().all(|rexp| re_nullable(rexp)),
+    &ReExpKind::Alternation(ref rexps) => rexps.iter().any(|rexp| re_nullable(rexp)),
+  }
+}
+
+/// Locate the construct of an unsupported HIR kind in the syntax of the
+/// pattern.
+#[cfg(feature = "regex")]
+fn re_unsupported_span(rstr: &str, kind: &str) -> CharSpan {
+  let whole = CharSpan{start: 0, end: rstr.len()};
+  let ast = match ReAstParser::new().parse(rstr) {
+    Err(_) => return whole,
+    Ok(ast) => ast
+  };
+  let found = re_ast_find(&ast, &|ast| match (kind, ast) {
+    ("Empty", &ReAst::Empty(_)) => true,
+    ("Alternation", &ReAst::Alternation(_)) => true,
+    ("Repetition", &ReAst::Repetition(ref r)) => match r.op.kind {
+      ReAstRepKind::Range(_) => true,
+      _ => match &*r.ast {
+        &ReAst::Group(_) | &ReAst::Concat(_) | &ReAst::Alternation(_) | &ReAst::Repetition(_) => true,
+        _ => false
+      }
+    },
+    _ => false
+  });
+  match found {
+    None => whole,
+    Some(ast) => {
+      let span = ast.span();
+      CharSpan{start: span.start.offset, end: span.end.offset}
+    }
+  }
+}
+
+#[cfg(feature = "regex")]
+fn re_ast_find<'a>(ast: &'a ReAst, pred: &dyn Fn(&ReAst) -> bool) -> Option<&'a ReAst> {
+  if pred(ast) {
+    return Some(ast);
+  }
+  match ast {
+    &ReAst::Repetition(ref r) => re_ast_find(&r.ast, pred),
+    &ReAst::Group(ref g) => re_ast_find(&g.ast, pred),
+    &ReAst::Alternation(ref a) => a.asts.iter().filter_map(|ast| re_ast_find(ast, pred)).next(),
+    &ReAst::Concat(ref c) => c.asts.iter().filter_map(|ast| re_ast_find(ast, pred)).next(),
+    _ => None
+  }
+}
+
+/// Split a trailing lookahead, `(?=...)` or `(?!...)`, off of a pattern.
+///
+/// Returns the rest of the pattern, whether the lookahead is negated, and
+/// the lookahead pattern.
+pub fn split_lookahead(rstr: &str) -> Option<(&str, bool, &str)> {
+  let span = *re_lookaheads(rstr).last()?;
+  if span.end != rstr.len() {
+    return None;
+  }
+  let negate = rstr[span.start .. ].starts_with("(?!");
+  Some((&rstr[ .. span.start], negate, &rstr[span.start + 3 .. span.end - 1]))
+}
+
+/// The inline flag groups (e.g. `(?i)`) at the top level of a pattern,
+/// which are in effect at its end, concatenated in order.
+#[cfg(feature = "regex")]
+fn re_trailing_flags(rstr: &str) -> String {
+  let bytes = rstr.as_bytes();
+  let mut flags = String::new();
+  let mut depth = 0;
+  let mut i = 0;
+  while i < bytes.len() {
+    match bytes[i] {
+      b'\\' => {
+        i += 2;
+        continue;
+      }
+      b'[' => {
+        i = re_skip_class(bytes, i);
+        continue;
+      }
+      b'(' => {
+        if depth == 0 && rstr[i .. ].starts_with("(?") {
+          let n = bytes[i + 2 .. ].iter().take_while(|&&b| b.is_ascii_alphabetic() || b == b'-').count();
+          if bytes.get(i + 2 + n) == Some(&b')') {
+            flags.push_str(&rstr[i .. i + 3 + n]);
+            i += 3 + n;
+            continue;
+          }
+        }
+        depth += 1;
+      }
+      b')' => depth -= 1,
+      _ => {}
+    }
+    i += 1;
+  }
+  flags
+}
+
+/// The spans of the closed lookahead groups of a pattern, in order of
+/// their closing parens. Escapes and char classes are skipped, so that
+/// e.g. `\(?=` and `[(?=]` do not open a group.
+fn re_lookaheads(rstr: &str) -> Vec<CharSpan> {
+  let bytes = rstr.as_bytes();
+  let mut spans = Vec::new();
+  let mut opens = Vec::new();
+  let mut i = 0;
+  while i < bytes.len() {
+    match bytes[i] {
+      b'\\' => {
+        i += 2;
+        continue;
+      }
+      b'[' => {
+        i = re_skip_class(bytes, i);
+        continue;
+      }
+      b'(' => {
+        let ahead = rstr[i .. ].starts_with("(?=") || rstr[i .. ].starts_with("(?!");
+        opens.push((i, ahead));
+      }
+      b')' => if let Some((start, true)) = opens.pop() {
+        spans.push(CharSpan{start, end: i + 1});
+      },
+      _ => {}
+    }
+    i += 1;
+  }
+  spans
+}
+
+/// The offset past the end of the char class starting at `bytes[start]`,
+/// which is a `[`.
+fn re_skip_class(bytes: &[u8], start: usize) -> usize {
+  let mut i = start + 1;
+  if bytes.get(i) == Some(&b'^') {
+    i += 1;
+  }
+  // NB: a leading `]` is a literal.
+  if bytes.get(i) == Some(&b']') {
+    i += 1;
+  }
+  let mut depth = 1;
+  while i < bytes.len() {
+    match bytes[i] {
+      b'\\' => {
+        i += 2;
+        continue;
+      }
+      // NB: nested classes, e.g. `[a[:digit:]]`.
+      b'[' => depth += 1,
+      b']' => {
+        depth -= 1;
+        if depth == 0 {
+          return i + 1;
+        }
+      }
+      _ => {}
+    }
+    i += 1;
+  }
+  bytes.len()
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum ReInnerTrieRef {
+  Terminal(usize),
+  Trie(ReInnerTrie),
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+struct ReInnerTrie {
+  index:    Option<usize>,
+  zero:     BTreeSet<usize>,
+  lit_term: ReMultiMap<char, usize>,
+  literal:  BTreeMap<char, ReInnerTrie>,
+  klass:    ReRangeMap<usize>,
+  bound1:   ReRangeMap<usize>,
+  unbound:  ReRangeMap<usize>,
+  ranges:   BTreeMap<usize, Vec<(char, char)>>,
+  asserts:  BTreeMap<usize, ReAssert>,
+  // The lazy repetition edges, and their maximum repetition count.
+  lazy:     BTreeMap<usize, Option<usize>>,
+  // The capture group bound edges, and their slot.
+  marks:    BTreeMap<usize, usize>,
+  non_lit:  Vec<ReInnerTrieRef>,
+  ascii:    ReAsciiCache,
+}
+
+/// The ASCII dispatch table of a trie node, built on the first match
+/// through the node.
+#[derive(Clone, Default, Debug)]
+struct ReAsciiCache(OnceCell<Box<ReAsciiTable>>);
+
+// NB: the table is a cache, and does not take part.
+impl PartialEq for ReAsciiCache {
+  fn eq(&self, _other: &ReAsciiCache) -> bool {
+    true
+  }
+}
+
+impl Eq for ReAsciiCache {}
+
+/// The edges of a trie node taken by each ASCII char, so that matching an
+/// ASCII char is an array lookup instead of a map search.
+#[derive(Clone, Debug)]
+struct ReAsciiTable {
+  // The slot of each ASCII char; chars taking the same edges share a slot.
+  dispatch: [u8; 128],
+  slots:    Vec<ReAsciiSlot>,
+  // The ASCII chars in the ranges of each edge, as a bitmask.
+  masks:    Vec<u128>,
+}
+
+#[derive(Clone, Default, PartialEq, Debug)]
+struct ReAsciiSlot {
+  lit_term: Vec<usize>,
+  klass:    Vec<usize>,
+  bound1:   Vec<usize>,
+  unbound:  Vec<usize>,
+}
+
+impl ReAsciiTable {
+  fn new(trie: &ReInnerTrie) -> ReAsciiTable {
+    let mut dispatch = [0; 128];
+    let mut slots: Vec<ReAsciiSlot> = Vec::new();
+    for b in 0 .. 128u8 {
+      let c = b as char;
+      let slot = ReAsciiSlot{
+        lit_term: trie.lit_term.values(c).collect(),
+        klass:    trie.klass.values_at(c).to_vec(),
+        bound1:   trie.bound1.values_at(c).to_vec(),
+        unbound:  trie.unbound.values_at(c).to_vec(),
+      };
+      dispatch[b as usize] = match slots.iter().position(|s| s == &slot) {
+        Some(i) => i as u8,
+        None => {
+          slots.push(slot);
+          (slots.len() - 1) as u8
+        }
+      };
+    }
+    let mut masks = Vec::with_capacity(trie.non_lit.len());
+    for eidx in 0 .. trie.non_lit.len() {
+      let mut mask = 0;
+      if let Some(ranges) = trie.ranges.get(&eidx) {
+        for &(lb, ub) in ranges.iter() {
+          for b in lb as u32 ..= (ub as u32).min(0x7f) {
+            mask |= 1_u128 << b;
+          }
+        }
+      }
+      masks.push(mask);
+    }
+    ReAsciiTable{dispatch, slots, masks}
+  }
+
+  fn slot(&self, c: char) -> &ReAsciiSlot {
+    &self.slots[self.dispatch[c as usize] as usize]
+  }
+}
+
+impl ReInnerTrie {
+  #[cfg(feature = "regex")]
+  fn new(index: usize) -> ReInnerTrie {
+    let mut trie = ReInnerTrie::default();
+    trie.index = Some(index);
+    trie
+  }
+
+  #[cfg(feature = "regex")]
+  fn _push(&mut self, atom: &ReAtom, atoms: &[ReAtom], ridx: usize) {
+    self.ascii = ReAsciiCache::default();
+    if let Some(idx) = self.index {
+      if idx != ridx {
+        self.index = None;
+      }
+    }
+    if let &ReAtom::Char(c) = atom {
+      if atoms.is_empty() {
+        self.lit_term.insert(c, ridx);
+      } else {
+        if !self.literal.contains_key(&c) {
+          self.literal.insert(c, ReInnerTrie::new(ridx));
+        }
+        let (atom, atoms) = atoms.split_first().unwrap();
+        self.literal.get_mut(&c).unwrap()._push(atom, atoms, ridx);
+      }
+      return;
+    }
+    let eidx = self.non_lit.len();
+    let ranges = match atom {
+      &ReAtom::Char(_) => unreachable!(),
+      &ReAtom::Class(ref ranges) => {
+        for &(lb, ub) in ranges.iter() {
+          self.klass.insert((lb, ub), eidx);
+        }
+        ranges.clone()
+      }
+      &ReAtom::Rep{ref ranges, zero, unbound, lazy} => {
+        if zero {
+          self.zero.insert(eidx);
+        }
+        if lazy {
+          self.lazy.insert(eidx, if unbound { None } else { Some(1) });
+        }
+        for &(lb, ub) in ranges.iter() {
+          if unbound {
+            self.unbound.insert((lb, ub), eidx);
+          } else {
+            self.bound1.insert((lb, ub), eidx);
+          }
+        }
+        ranges.clone()
+      }
+      &ReAtom::Assert(assert, ref ranges) => {
+        self.zero.insert(eidx);
+        self.asserts.insert(eidx, assert);
+        ranges.clone()
+      }
+      &ReAtom::Mark(slot) => {
+        self.zero.insert(eidx);
+        self.marks.insert(eidx, slot);
+        Vec::new()
+      }
+    };
+    self.ranges.insert(eidx, ranges);
+    let next = match atoms.split_first() {
+      None => ReInnerTrieRef::Terminal(ridx),
+      Some((atom, atoms)) => {
+        let mut trie = ReInnerTrie::new(ridx);
+        trie._push(atom, atoms, ridx);
+        ReInnerTrieRef::Trie(trie)
+      }
+    };
+    self.non_lit.push(next);
+  }
+
+  fn _match(&self, query: &str, ctx: Option<usize>, prev: Option<char>) -> Option<(usize, usize)> {
+    self._match_traced(query, ctx, prev, None)
+  }
+
+  fn _ascii(&self) -> &ReAsciiTable {
+    self.ascii.0.get_or_init(|| Box::new(ReAsciiTable::new(self)))
+  }
+
+  fn _match_traced(&self, query: &str, ctx: Option<usize>, prev: Option<char>, tr: Option<&mut ReTracer>) -> Option<(usize, usize)> {
+    let tr = match tr {
+      None => return self._match_node(query, ctx, prev, None),
+      Some(tr) => tr
+    };
+    let node = *tr.ids.get(&(self as *const ReInnerTrie)).unwrap();
+    let pos = tr.end - query.len();
+    (tr.f)(ReTrace::Enter{node, pos, rule: ctx.map(RuleId)});
+    let mat = self._match_node(query, ctx, prev, Some(&mut *tr));
+    (tr.f)(ReTrace::Exit{node, pos, mat: mat.map(|(mat_len, ridx)| (RuleId(ridx), pos + mat_len))});
+    mat
+  }
+
+  fn _match_node(&self, query: &str, ctx: Option<usize>, prev: Option<char>, mut tr: Option<&mut ReTracer>) -> Option<(usize, usize)> {
+    match (ctx, self.index) {
+      (Some(ctx), Some(idx)) => if ctx != idx {
+        return None;
+      },
+      _ => {}
+    }
+    let ctx = ctx.or(self.index);
+    let c = match query.as_bytes().first() {
+      None => {
+        assert_eq!(query.len(), 0);
+        let mut mat = None;
+        for &eidx in self.zero.iter() {
+          if !self._assert_holds(eidx, prev, None) {
+            continue;
+          }
+          match &self.non_lit[eidx] {
+            &ReInnerTrieRef::Terminal(ridx) => match (ctx, mat) {
+              (None, None) => {
+                mat = Some((0, ridx));
+              }
+              (None, Some((_, old_idx))) => if ridx < old_idx {
+                mat = Some((0, ridx));
+              }
+              (Some(ctx), _) => if ctx == ridx {
+                mat = Some((0, ridx));
+                break;
+              }
+            }
+            &ReInnerTrieRef::Trie(ref trie) => match trie._match_traced(query, ctx, prev, tr.as_deref_mut()) {
+              None => {}
+              Some((_, ridx)) => match (ctx, mat) {
+                (None, None) => {
+                  mat = Some((0, ridx));
+                }
+                (None, Some((_, old_idx))) => if ridx < old_idx {
+                  mat = Some((0, ridx));
+                }
+                (Some(ctx), _) => if ctx == ridx {
+                  mat = Some((0, ridx));
+                  break;
+                }
+              }
+            }
+          }
+        }
+        return mat;
+      }
+      // NB: an ASCII char is its byte, with no decoding.
+      Some(&b) if b < 0x80 => b as char,
+      Some(_) => query.chars().next().unwrap()
+    };
+    let c_len = len_utf8(c as _);
+    let suffix = match query.get(c_len .. ) {
+      None => unreachable!(),
+      Some(s) => s
+    };
+    let ascii = self._ascii();
+    let slot = if c_len == 1 { Some(ascii.slot(c)) } else { None };
+    let mut mat = None;
+    // FIXME: think about this.
+    let mut zero_mat = None;
+    for &eidx in self.zero.iter() {
+      // NB: lazy repetitions are matched below, as a whole.
+      if self.lazy.contains_key(&eidx) || !self._assert_holds(eidx, prev, Some(c)) {
+        continue;
+      }
+      match &self.non_lit[eidx] {
+        &ReInnerTrieRef::Terminal(ridx) => match (ctx, zero_mat) {
+          (None, None) => {
+            zero_mat = Some((0, ridx));
+          }
+          (None, Some((old_len, old_idx))) => if ridx < old_idx {
+            assert_eq!(old_len, 0);
+            zero_mat = Some((0, ridx));
+          }
+          (Some(ctx), _) => if ctx == ridx {
+            zero_mat = Some((0, ridx));
+            break;
+          }
+        }
+        &ReInnerTrieRef::Trie(ref trie) => match trie._match_traced(query, ctx, prev, tr.as_deref_mut()) {
+          None => {}
+          Some((0, ridx)) => match (ctx, zero_mat) {
+            (None, None) => {
+              zero_mat = Some((0, ridx));
+            }
+            (None, Some((old_len, old_idx))) => if ridx < old_idx {
+              assert_eq!(old_len, 0);
+              zero_mat = Some((0, ridx));
             }
             (Some(ctx), _) => if ctx == ridx {
-              //println!("TRACE: re:   repeat0: trie/term S _ ({})", ridx);
               zero_mat = Some((0, ridx));
               break;
             }
           }
-          Some((mat_len, ridx)) => match (ctx, mat) {
-            (None, None) => {
-              //println!("TRACE: re:   repeat0: trie N N");
-              mat = Some((mat_len, ridx));
+          Some((mat_len, ridx)) => match (ctx, mat) {
+            (None, None) => {
+              mat = Some((mat_len, ridx));
+            }
+            (None, Some((old_len, old_idx))) => if old_len < mat_len
+                || old_len == mat_len && ridx < old_idx {
+              mat = Some((mat_len, ridx));
+            }
+            (Some(ctx), _) => if ctx == ridx {
+              mat = Some((mat_len, ridx));
+              break;
+            }
+          }
+        }
+      }
+    }
+    for (&eidx, &max) in self.lazy.iter() {
+      let (rep_len, ridx) = match self._match_lazy(eidx, max, query, ctx, prev, tr.as_deref_mut()) {
+        None => continue,
+        Some(m) => m
+      };
+      if rep_len == 0 {
+        match (ctx, zero_mat) {
+          (None, None) => {
+            zero_mat = Some((0, ridx));
+          }
+          (None, Some((_, old_idx))) => if ridx < old_idx {
+            zero_mat = Some((0, ridx));
+          }
+          (Some(ctx), _) => if ctx == ridx {
+            zero_mat = Some((0, ridx));
+          }
+        }
+        continue;
+      }
+      match (ctx, mat) {
+        (None, None) => {
+          mat = Some((rep_len, ridx));
+        }
+        (None, Some((old_len, old_idx))) => if old_len < rep_len
+            || old_len == rep_len && ridx < old_idx {
+          mat = Some((rep_len, ridx));
+        }
+        (Some(ctx), _) => if ctx == ridx {
+          mat = Some((rep_len, ridx));
+          return mat;
+        }
+      }
+    }
+    let lit_term: Vec<usize>;
+    let lit_term = match slot {
+      Some(slot) => &slot.lit_term,
+      None => {
+        lit_term = self.lit_term.values(c).collect();
+        &lit_term
+      }
+    };
+    for &ridx in lit_term.iter() {
+      match (ctx, mat) {
+        (None, None) => {
+          mat = Some((c_len, ridx));
+        }
+        (None, Some((old_len, old_idx))) => if old_len < c_len
+            || old_len == c_len && ridx < old_idx {
+          mat = Some((c_len, ridx));
+        }
+        (Some(ctx), _) => if ctx == ridx {
+          mat = Some((c_len, ridx));
+          return mat;
+        }
+      }
+    }
+    if let Some(trie) = self.literal.get(&c) {
+      match trie._match_traced(suffix, ctx, Some(c), tr.as_deref_mut()) {
+        None => {}
+        Some((suffix_len, ridx)) => match (ctx, mat) {
+          (None, None) => {
+            mat = Some((c_len + suffix_len, ridx));
+          }
+          (None, Some((old_len, old_idx))) => if old_len < c_len + suffix_len
+              || old_len == c_len + suffix_len && ridx < old_idx {
+            mat = Some((c_len + suffix_len, ridx));
+          }
+          (Some(ctx), _) => if ctx == ridx {
+            mat = Some((c_len + suffix_len, ridx));
+            return mat;
+          }
+        }
+      }
+    }
+    let klass = match slot {
+      Some(slot) => &slot.klass,
+      None => self.klass.values_at(c)
+    };
+    for &eidx in klass.iter() {
+      match &self.non_lit[eidx] {
+        &ReInnerTrieRef::Terminal(ridx) => match (ctx, mat) {
+          (None, None) => {
+            mat = Some((c_len, ridx));
+          }
+          (None, Some((old_len, old_idx))) => if old_len < c_len
+              || old_len == c_len && ridx < old_idx {
+            mat = Some((c_len, ridx));
+          }
+          (Some(ctx), _) => if ctx == ridx {
+            mat = Some((c_len, ridx));
+            return mat;
+          }
+        }
+        &ReInnerTrieRef::Trie(ref trie) => match trie._match_traced(suffix, ctx, Some(c), tr.as_deref_mut()) {
+          None => {}
+          Some((suffix_len, ridx)) => match (ctx, mat) {
+            (None, None) => {
+              mat = Some((c_len + suffix_len, ridx));
+            }
+            (None, Some((old_len, old_idx))) => if old_len < c_len + suffix_len
+                || old_len == c_len + suffix_len && ridx < old_idx {
+              mat = Some((c_len + suffix_len, ridx));
+            }
+            (Some(ctx), _) => if ctx == ridx {
+              mat = Some((c_len + suffix_len, ridx));
+              return mat;
+            }
+          }
+        }
+      }
+    }
+    let bound1 = match slot {
+      Some(slot) => &slot.bound1,
+      None => self.bound1.values_at(c)
+    };
+    for &eidx in bound1.iter() {
+      if self.lazy.contains_key(&eidx) {
+        continue;
+      }
+      match &self.non_lit[eidx] {
+        &ReInnerTrieRef::Terminal(ridx) => match (ctx, mat) {
+          (None, None) => {
+            mat = Some((c_len, ridx));
+          }
+          (None, Some((old_len, old_idx))) => if old_len < c_len
+              || old_len == c_len && ridx < old_idx {
+            mat = Some((c_len, ridx));
+          }
+          (Some(ctx), _) => if ctx == ridx {
+            mat = Some((c_len, ridx));
+            return mat;
+          }
+        }
+        &ReInnerTrieRef::Trie(ref trie) => match trie._match_traced(suffix, ctx, Some(c), tr.as_deref_mut()) {
+          None => {}
+          Some((suffix_len, ridx)) => match (ctx, mat) {
+            (None, None) => {
+              mat = Some((c_len + suffix_len, ridx));
+            }
+            (None, Some((old_len, old_idx))) => if old_len < c_len + suffix_len
+                || old_len == c_len + suffix_len && ridx < old_idx {
+              mat = Some((c_len + suffix_len, ridx));
+            }
+            (Some(ctx), _) => if ctx == ridx {
+              mat = Some((c_len + suffix_len, ridx));
+              return mat;
+            }
+          }
+        }
+      }
+    }
+    let unbound = match slot {
+      Some(slot) => &slot.unbound,
+      None => self.unbound.values_at(c)
+    };
+    for &eidx in unbound.iter() {
+      if self.lazy.contains_key(&eidx) {
+        continue;
+      }
+      let ranges = self.ranges.get(&eidx).unwrap();
+      let mask = ascii.masks[eidx];
+      let mut rep_len = c_len;
+      let mut suffix = suffix;
+      let mut last = c;
+      loop {
+        // NB: runs of ASCII chars are scanned bytewise against the mask.
+        let run = suffix.bytes().take_while(|&b| b < 0x80 && mask & (1_u128 << b) != 0).count();
+        if run > 0 {
+          rep_len += run;
+          last = suffix.as_bytes()[run - 1] as char;
+          suffix = suffix.get(run .. ).unwrap();
+        }
+        let c = match suffix.chars().next() {
+          None => break,
+          Some(c) => c
+        };
+        if (c as u32) < 0x80 || !ranges_contain(ranges, c) {
+          break;
+        }
+        let c_len = len_utf8(c as _);
+        rep_len += c_len;
+        suffix = suffix.get(c_len .. ).unwrap();
+        last = c;
+      }
+      match &self.non_lit[eidx] {
+        &ReInnerTrieRef::Terminal(ridx) => match (ctx, mat) {
+          (None, None) => {
+            mat = Some((rep_len, ridx));
+          }
+          (None, Some((old_len, old_idx))) => if old_len < rep_len
+              || old_len == rep_len && ridx < old_idx {
+            mat = Some((rep_len, ridx));
+          }
+          (Some(ctx), _) => if ctx == ridx {
+            mat = Some((rep_len, ridx));
+            return mat;
+          }
+        }
+        &ReInnerTrieRef::Trie(ref trie) => match trie._match_traced(suffix, ctx, Some(last), tr.as_deref_mut()) {
+          None => {}
+          Some((suffix_len, ridx)) => match (ctx, mat) {
+            (None, None) => {
+              mat = Some((rep_len + suffix_len, ridx));
+            }
+            (None, Some((old_len, old_idx))) => if old_len < rep_len + suffix_len
+                || old_len == rep_len + suffix_len && ridx < old_idx {
+              mat = Some((rep_len + suffix_len, ridx));
+            }
+            (Some(ctx), _) => if ctx == ridx {
+              mat = Some((rep_len + suffix_len, ridx));
+              return mat;
+            }
+          }
+        }
+      }
+    }
+    mat = mat.or(zero_mat);
+    mat
+  }
+
+  /// Match every rule at once, giving for each rule that matches the
+  /// length of the match of `_match` restricted to the rule.
+  ///
+  /// NB: the restricted walk returns the first match of the rule, by the
+  /// order of the edge kinds below; matches through zero edges rank after
+  /// the others, and zero length ones last.
+  fn _match_each(&self, query: &str, prev: Option<char>) -> BTreeMap<usize, usize> {
+    let mut mats = BTreeMap::new();
+    let c = match query.chars().next() {
+      None => {
+        for &eidx in self.zero.iter() {
+          if !self._assert_holds(eidx, prev, None) {
+            continue;
+          }
+          match &self.non_lit[eidx] {
+            &ReInnerTrieRef::Terminal(ridx) => {
+              mats.entry(ridx).or_insert(0);
             }
-            (None, Some((old_len, old_idx))) => if old_len < mat_len
-                || old_len == mat_len && ridx < old_idx {
-              //println!("TRACE: re:   repeat0: trie N S");
-              mat = Some((mat_len, ridx));
+            &ReInnerTrieRef::Trie(ref trie) => for (ridx, _) in trie._match_each(query, prev) {
+              mats.entry(ridx).or_insert(0);
             }
-            (Some(ctx), _) => if ctx == ridx {
-              //println!("TRACE: re:   repeat0: trie S _");
-              mat = Some((mat_len, ridx));
-              break;
+          }
+        }
+        self._retain_index(&mut mats);
+        return mats;
+      }
+      Some(c) => c
+    };
+    let c_len = len_utf8(c as _);
+    let suffix = query.get(c_len .. ).unwrap();
+    let mut zero_mats = BTreeMap::new();
+    for &eidx in self.zero.iter() {
+      if self.lazy.contains_key(&eidx) || !self._assert_holds(eidx, prev, Some(c)) {
+        continue;
+      }
+      match &self.non_lit[eidx] {
+        &ReInnerTrieRef::Terminal(ridx) => {
+          zero_mats.entry(ridx).or_insert(0);
+        }
+        &ReInnerTrieRef::Trie(ref trie) => for (ridx, mat_len) in trie._match_each(query, prev) {
+          zero_mats.entry(ridx).or_insert(mat_len);
+        }
+      }
+    }
+    let mut lazy_zero_mats = BTreeMap::new();
+    for (&eidx, &max) in self.lazy.iter() {
+      for (ridx, rep_len) in self._match_lazy_each(eidx, max, query, prev) {
+        if rep_len == 0 {
+          lazy_zero_mats.insert(ridx, 0);
+        } else {
+          mats.entry(ridx).or_insert(rep_len);
+        }
+      }
+    }
+    for ridx in self.lit_term.values(c) {
+      mats.entry(ridx).or_insert(c_len);
+    }
+    if let Some(trie) = self.literal.get(&c) {
+      for (ridx, suffix_len) in trie._match_each(suffix, Some(c)) {
+        mats.entry(ridx).or_insert(c_len + suffix_len);
+      }
+    }
+    let edges = self.klass.values_at(c).iter()
+      .chain(self.bound1.values_at(c).iter())
+      .filter(|&eidx| !self.lazy.contains_key(eidx));
+    for &eidx in edges {
+      match &self.non_lit[eidx] {
+        &ReInnerTrieRef::Terminal(ridx) => {
+          mats.entry(ridx).or_insert(c_len);
+        }
+        &ReInnerTrieRef::Trie(ref trie) => for (ridx, suffix_len) in trie._match_each(suffix, Some(c)) {
+          mats.entry(ridx).or_insert(c_len + suffix_len);
+        }
+      }
+    }
+    for &eidx in self.unbound.values_at(c).iter() {
+      if self.lazy.contains_key(&eidx) {
+        continue;
+      }
+      let ranges = self.ranges.get(&eidx).unwrap();
+      let mut rep_len = c_len;
+      let mut suffix = suffix;
+      let mut last = c;
+      while let Some(c) = suffix.chars().next() {
+        if !ranges_contain(ranges, c) {
+          break;
+        }
+        let c_len = len_utf8(c as _);
+        rep_len += c_len;
+        suffix = suffix.get(c_len .. ).unwrap();
+        last = c;
+      }
+      match &self.non_lit[eidx] {
+        &ReInnerTrieRef::Terminal(ridx) => {
+          mats.entry(ridx).or_insert(rep_len);
+        }
+        &ReInnerTrieRef::Trie(ref trie) => for (ridx, suffix_len) in trie._match_each(suffix, Some(last)) {
+          mats.entry(ridx).or_insert(rep_len + suffix_len);
+        }
+      }
+    }
+    for (ridx, mat_len) in zero_mats.iter() {
+      if *mat_len > 0 {
+        mats.entry(*ridx).or_insert(*mat_len);
+      }
+    }
+    for (ridx, mat_len) in lazy_zero_mats.into_iter().chain(zero_mats.into_iter()) {
+      mats.entry(ridx).or_insert(mat_len);
+    }
+    self._retain_index(&mut mats);
+    mats
+  }
+
+  fn _retain_index(&self, mats: &mut BTreeMap<usize, usize>) {
+    if let Some(idx) = self.index {
+      mats.retain(|&ridx, _| ridx == idx);
+    }
+  }
+
+  /// Match a lazy repetition edge for every rule at once; see `_match_lazy`
+  /// and `_match_each`.
+  fn _match_lazy_each(&self, eidx: usize, max: Option<usize>, query: &str, prev: Option<char>) -> BTreeMap<usize, usize> {
+    let ranges = self.ranges.get(&eidx).unwrap();
+    let min = if self.zero.contains(&eidx) { 0 } else { 1 };
+    let mut mats = BTreeMap::new();
+    let mut rep_len = 0;
+    let mut count = 0;
+    let mut prev = prev;
+    loop {
+      if count >= min {
+        match &self.non_lit[eidx] {
+          &ReInnerTrieRef::Terminal(ridx) => {
+            mats.entry(ridx).or_insert(rep_len);
+          }
+          &ReInnerTrieRef::Trie(ref trie) => for (ridx, suffix_len) in trie._match_each(&query[rep_len .. ], prev) {
+            mats.entry(ridx).or_insert(rep_len + suffix_len);
+          }
+        }
+      }
+      if Some(count) == max {
+        return mats;
+      }
+      let c = match query[rep_len .. ].chars().next() {
+        None => return mats,
+        Some(c) => c
+      };
+      if !ranges_contain(ranges, c) {
+        return mats;
+      }
+      rep_len += len_utf8(c as _);
+      count += 1;
+      prev = Some(c);
+    }
+  }
+
+  /// Match a lazy repetition edge, taking as few repetitions as possible
+  /// for the rest of the pattern to match.
+  fn _match_lazy(&self, eidx: usize, max: Option<usize>, query: &str, ctx: Option<usize>, prev: Option<char>, mut tr: Option<&mut ReTracer>) -> Option<(usize, usize)> {
+    let ranges = self.ranges.get(&eidx).unwrap();
+    let min = if self.zero.contains(&eidx) { 0 } else { 1 };
+    let mut rep_len = 0;
+    let mut count = 0;
+    let mut prev = prev;
+    loop {
+      if count >= min {
+        match &self.non_lit[eidx] {
+          &ReInnerTrieRef::Terminal(ridx) => {
+            if ctx.map(|ctx| ctx == ridx).unwrap_or(true) {
+              return Some((rep_len, ridx));
+            }
+          }
+          &ReInnerTrieRef::Trie(ref trie) => {
+            if let Some((suffix_len, ridx)) = trie._match_traced(&query[rep_len .. ], ctx, prev, tr.as_deref_mut()) {
+              return Some((rep_len + suffix_len, ridx));
             }
           }
         }
       }
+      if Some(count) == max {
+        return None;
+      }
+      let c = query[rep_len .. ].chars().next()?;
+      if !ranges_contain(ranges, c) {
+        return None;
+      }
+      rep_len += len_utf8(c as _);
+      count += 1;
+      prev = Some(c);
+    }
+  }
+
+  fn _first(&self) -> ReFirst {
+    let mut first = ReFirst::default();
+    self._collect_first(&mut first);
+    // NB: `ranges_contain` needs sorted, disjoint ranges.
+    first.ranges.sort();
+    let mut ranges: Vec<(char, char)> = Vec::new();
+    for (lb, ub) in first.ranges.drain( .. ) {
+      match ranges.last_mut() {
+        Some(last) if lb <= last.1 => last.1 = last.1.max(ub),
+        _ => ranges.push((lb, ub))
+      }
+    }
+    first.ranges = ranges;
+    first
+  }
+
+  fn _collect_first(&self, first: &mut ReFirst) {
+    for c in self.lit_term.keys() {
+      first.insert(c, c);
+    }
+    for &c in self.literal.keys() {
+      first.insert(c, c);
+    }
+    for (eidx, r) in self.non_lit.iter().enumerate() {
+      if !self.asserts.contains_key(&eidx) && !self.marks.contains_key(&eidx) {
+        for &(lb, ub) in self.ranges.get(&eidx).unwrap().iter() {
+          first.insert(lb, ub);
+        }
+      }
+      if self.zero.contains(&eidx) {
+        match r {
+          &ReInnerTrieRef::Terminal(_) => first.empty = true,
+          &ReInnerTrieRef::Trie(ref trie) => trie._collect_first(first),
+        }
+      }
+    }
+  }
+
+  fn _assert_holds(&self, eidx: usize, prev: Option<char>, next: Option<char>) -> bool {
+    match self.asserts.get(&eidx) {
+      None => true,
+      Some(assert) => assert.holds(self.ranges.get(&eidx).unwrap(), prev, next)
+    }
+  }
+}
+
+/// A step of the walk of a trie by `ReRuleTrie::match_at_traced`. Nodes
+/// are numbered in preorder, as by `dump` and `to_dot`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReTrace {
+  /// The walk enters `node` at `pos`; if `rule` is set, only that rule is
+  /// matched from the node.
+  Enter{node: usize, pos: usize, rule: Option<RuleId>},
+  /// The walk leaves `node`, entered at `pos`, with the best match from the
+  /// node, if any: its rule, and end position.
+  Exit{node: usize, pos: usize, mat: Option<(RuleId, usize)>},
+}
+
+struct ReTracer<'a> {
+  ids:      BTreeMap<*const ReInnerTrie, usize>,
+  // The length of the text, so that positions can be found from the
+  // length of the rest of the text.
+  end:      usize,
+  f:        &'a mut dyn FnMut(ReTrace),
+}
+
+impl ReRuleTrie {
+  /// Match at `pos` in `text`, as `match_at`, reporting each node visited
+  /// by the walk of the trie to `trace`.
+  pub fn match_at_traced(&self, text: &str, pos: usize, trace: &mut dyn FnMut(ReTrace)) -> Option<(RuleId, usize)> {
+    let query = match text.get(pos .. ) {
+      None => panic!("bug"),
+      Some(s) => s
+    };
+    let prev = text[ .. pos].chars().next_back();
+    let mut nodes = Vec::new();
+    self.inner._collect_static(&mut nodes);
+    let ids = nodes.iter().enumerate().map(|(id, &n)| (n as *const ReInnerTrie, id)).collect();
+    let mut tr = ReTracer{ids, end: text.len(), f: trace};
+    let (mat_len, ridx) = self.inner._match_traced(query, None, prev, Some(&mut tr))?;
+    Some((RuleId(ridx), pos + mat_len))
+  }
+
+  /// Render the nodes of the trie as text, numbered in preorder.
+  ///
+  /// Each node lists its edges: literal chars ending a rule (`'a' => rule
+  /// 1`), literal chars leading to another node (`'b' -> 2`), and the other
+  /// edges, written as patterns (`#0 [0-9a-f]+ -> 3`).
+  pub fn dump(&self) -> String {
+    let mut nodes = Vec::new();
+    self.inner._collect_static(&mut nodes);
+    let mut w = String::new();
+    for (id, node) in nodes.iter().enumerate() {
+      match node.index {
+        None => writeln!(&mut w, "node {}", id).unwrap(),
+        Some(ridx) => writeln!(&mut w, "node {} (rule {})", id, ridx).unwrap()
+      }
+      for (c, ridx) in node.lit_term.entries().into_iter() {
+        writeln!(&mut w, "  {:?} => rule {}", c, ridx).unwrap();
+      }
+      for (&c, trie) in node.literal.iter() {
+        writeln!(&mut w, "  {:?} -> {}", c, trie._static_id(&nodes)).unwrap();
+      }
+      for (eidx, r) in node.non_lit.iter().enumerate() {
+        write!(&mut w, "  #{} {}", eidx, node._edge_label(eidx)).unwrap();
+        match r {
+          &ReInnerTrieRef::Terminal(ridx) => writeln!(&mut w, " => rule {}", ridx).unwrap(),
+          &ReInnerTrieRef::Trie(ref trie) => writeln!(&mut w, " -> {}", trie._static_id(&nodes)).unwrap(),
+        }
+      }
+    }
+    w
+  }
+
+  /// Render the trie as a Graphviz DOT digraph. Nodes are numbered as by
+  /// `dump`, and each rule has a terminal node of its own; edges that can
+  /// be taken without consuming a char are dashed.
+  pub fn to_dot(&self) -> String {
+    let mut nodes = Vec::new();
+    self.inner._collect_static(&mut nodes);
+    let mut w = String::new();
+    writeln!(&mut w, "digraph retrie {{").unwrap();
+    writeln!(&mut w, "  node [shape=circle];").unwrap();
+    let mut rules = BTreeSet::new();
+    for (id, node) in nodes.iter().enumerate() {
+      match node.index {
+        None => writeln!(&mut w, "  n{} [label=\"{}\"];", id, id).unwrap(),
+        Some(ridx) => writeln!(&mut w, "  n{} [label=\"{}\\nrule {}\"];", id, id, ridx).unwrap()
+      }
+      for (c, ridx) in node.lit_term.entries().into_iter() {
+        writeln!(&mut w, "  n{} -> r{} [label=\"{}\"];", id, ridx, dot_escape(&format!("{:?}", c))).unwrap();
+        rules.insert(ridx);
+      }
+      for (&c, trie) in node.literal.iter() {
+        writeln!(&mut w, "  n{} -> n{} [label=\"{}\"];", id, trie._static_id(&nodes), dot_escape(&format!("{:?}", c))).unwrap();
+      }
+      for (eidx, r) in node.non_lit.iter().enumerate() {
+        let next = match r {
+          &ReInnerTrieRef::Terminal(ridx) => {
+            rules.insert(ridx);
+            format!("r{}", ridx)
+          }
+          &ReInnerTrieRef::Trie(ref trie) => format!("n{}", trie._static_id(&nodes)),
+        };
+        let style = if node.zero.contains(&eidx) { ", style=dashed" } else { "" };
+        writeln!(&mut w, "  n{} -> {} [label=\"{}\"{}];", id, next, dot_escape(&node._edge_label(eidx)), style).unwrap();
+      }
+    }
+    for ridx in rules.into_iter() {
+      writeln!(&mut w, "  r{} [shape=doublecircle, label=\"rule {}\"];", ridx, ridx).unwrap();
+    }
+    writeln!(&mut w, "}}").unwrap();
+    w
+  }
+}
+
+impl<T> ReTrie<T> {
+  /// Render the nodes of the trie as text; see `ReRuleTrie::dump`.
+  pub fn dump(&self) -> String {
+    self.rules.dump()
+  }
+
+  /// Render the trie as a Graphviz DOT digraph; see `ReRuleTrie::to_dot`.
+  pub fn to_dot(&self) -> String {
+    self.rules.to_dot()
+  }
+}
+
+impl ReInnerTrie {
+  /// A non-literal edge, written as a pattern.
+  fn _edge_label(&self, eidx: usize) -> String {
+    let ranges = self.ranges.get(&eidx).unwrap();
+    let zero = self.zero.contains(&eidx);
+    match self._static_kind(eidx) {
+      ReStaticKind::Class => ranges_label(ranges),
+      ReStaticKind::Bound1 => format!("{}{}", ranges_label(ranges), if zero { "?" } else { "" }),
+      ReStaticKind::Unbound => format!("{}{}", ranges_label(ranges), if zero { "*" } else { "+" }),
+      ReStaticKind::Bound1Lazy => format!("{}{}", ranges_label(ranges), if zero { "??" } else { "" }),
+      ReStaticKind::UnboundLazy => format!("{}{}", ranges_label(ranges), if zero { "*?" } else { "+?" }),
+      ReStaticKind::Assert(assert @ ReAssert::Ahead) |
+      ReStaticKind::Assert(assert @ ReAssert::NotAhead) => format!("{:?}{}", assert, ranges_label(ranges)),
+      ReStaticKind::Assert(assert) => format!("{:?}", assert),
+      ReStaticKind::Mark(slot) => if slot % 2 == 0 {
+        format!("({}", slot / 2)
+      } else {
+        format!("{})", slot / 2)
+      },
+    }
+  }
+}
+
+/// Write `ranges` as a char class, eliding all but the first few ranges.
+fn ranges_label(ranges: &[(char, char)]) -> String {
+  let mut label = String::from("[");
+  for &(lb, ub) in ranges.iter().take(8) {
+    label.extend(lb.escape_debug());
+    if lb < ub {
+      label.push('-');
+      label.extend(ub.escape_debug());
+    }
+  }
+  if ranges.len() > 8 {
+    write!(&mut label, "...+{}", ranges.len() - 8).unwrap();
+  }
+  label.push(']');
+  label
+}
+
+fn dot_escape(s: &str) -> String {
+  s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// A node of a `ReTrie` compiled into static tables.
+///
+/// Node 0 is the root; `literal` and `ReStaticRef::Trie` refer to other
+/// nodes by their position in the table. Entries are listed in the order
+/// in which they were inserted, so that reloading them reproduces the
+/// original trie.
+pub struct ReStaticNode {
+  pub index:    Option<usize>,
+  pub lit_term: &'static [(char, usize)],
+  pub literal:  &'static [(char, usize)],
+  pub non_lit:  &'static [ReStaticEdge],
+}
+
+/// A class or repetition edge of a `ReStaticNode`.
+pub struct ReStaticEdge {
+  pub kind:     ReStaticKind,
+  pub zero:     bool,
+  pub ranges:   &'static [(char, char)],
+  pub next:     ReStaticRef,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReStaticKind {
+  Class,
+  Bound1,
+  Unbound,
+  /// A zero-width assertion; see `ReAssert`.
+  Assert(ReAssert),
+  Bound1Lazy,
+  UnboundLazy,
+  /// The start (`2 * group`) or end (`2 * group + 1`) of a capture group.
+  Mark(usize),
+}
+
+pub enum ReStaticRef {
+  Terminal(usize),
+  Trie(usize),
+}
+
+pub struct ReStaticTrie {
+  pub nrules:   usize,
+  pub nodes:    &'static [ReStaticNode],
+  /// The named capture groups, as `(rule, group, name)`.
+  pub names:    &'static [(usize, usize, &'static str)],
+}
+
+/// Writes tries as Rust source for `ReStaticTrie` statics, for use with
+/// `ReRuleTrie::from_static` or `ReTrie::from_static`. Range tables are
+/// shared among all of the written tries, as large Unicode classes tend
+/// to recur.
+#[derive(Default)]
+pub struct ReStaticWriter {
+  ranges:   Vec<Vec<(char, char)>>,
+  tries:    String,
+}
+
+impl ReStaticWriter {
+  pub fn new() -> ReStaticWriter {
+    ReStaticWriter::default()
+  }
+
+  pub fn write_trie(&mut self, name: &str, trie: &ReRuleTrie) -> FmtResult {
+    let mut nodes = Vec::new();
+    trie.inner._collect_static(&mut nodes);
+    let mut w = String::new();
+    writeln!(&mut w)?;
+    writeln!(&mut w, "pub static {}: ReStaticTrie = ReStaticTrie{{", name)?;
+    writeln!(&mut w, "  nrules:   {},", trie.nrules)?;
+    writeln!(&mut w, "  nodes:    &[")?;
+    for (id, node) in nodes.iter().enumerate() {
+      node._write_static(id, &nodes, self, &mut w)?;
+    }
+    writeln!(&mut w, "  ],")?;
+    write!(&mut w, "  names:    &[")?;
+    for (&ridx, names) in trie.names.iter() {
+      for &(group, ref name) in names.iter() {
+        write!(&mut w, "\n    ({}, {}, {:?}),", ridx, group, name.as_str())?;
+      }
+    }
+    if !trie.names.is_empty() {
+      write!(&mut w, "\n  ")?;
+    }
+    writeln!(&mut w, "],")?;
+    writeln!(&mut w, "}};")?;
+    self.tries.push_str(&w);
+    Ok(())
+  }
+
+  fn _ranges_id(&mut self, ranges: &[(char, char)]) -> usize {
+    match self.ranges.iter().position(|r| &r[ .. ] == ranges) {
+      Some(id) => id,
+      None => {
+        self.ranges.push(ranges.to_vec());
+        self.ranges.len() - 1
+      }
+    }
+  }
+
+  /// The source of the range tables and the tries written so far.
+  pub fn finish<W: FmtWrite>(&self, w: &mut W) -> FmtResult {
+    writeln!(w, "use crate::re::{{ReStaticEdge, ReStaticKind, ReStaticNode, ReStaticRef, ReStaticTrie}};")?;
+    for (id, ranges) in self.ranges.iter().enumerate() {
+      writeln!(w)?;
+      write!(w, "static RANGES_{}: &'static [(char, char)] = &[", id)?;
+      for (i, &(lb, ub)) in ranges.iter().enumerate() {
+        if i % 6 == 0 {
+          write!(w, "\n  ")?;
+        } else {
+          write!(w, " ")?;
+        }
+        write!(w, "(")?;
+        write_static_char(lb, w)?;
+        write!(w, ", ")?;
+        write_static_char(ub, w)?;
+        write!(w, "),")?;
+      }
+      writeln!(w, "\n];")?;
+    }
+    write!(w, "{}", self.tries)
+  }
+}
+
+fn write_static_char<W: FmtWrite>(c: char, w: &mut W) -> FmtResult {
+  if c.is_ascii() {
+    write!(w, "{:?}", c)
+  } else {
+    write!(w, "'\\u{{{:x}}}'", c as u32)
+  }
+}
+
+impl<K: Copy + Ord, V: Copy + Ord> ReMultiMap<K, V> {
+  fn entries(&self) -> Vec<(K, V)> {
+    let mut es = Vec::new();
+    for k in self.keys() {
+      for v in self.values(k) {
+        es.push((k, v));
+      }
+    }
+    es
+  }
+}
+
+impl ReInnerTrie {
+  fn _collect_static<'a>(&'a self, nodes: &mut Vec<&'a ReInnerTrie>) {
+    nodes.push(self);
+    for (_, trie) in self.literal.iter() {
+      trie._collect_static(nodes);
+    }
+    for r in self.non_lit.iter() {
+      if let &ReInnerTrieRef::Trie(ref trie) = r {
+        trie._collect_static(nodes);
+      }
+    }
+  }
+
+  fn _static_id(&self, nodes: &[&ReInnerTrie]) -> usize {
+    nodes.iter().position(|&n| n as *const ReInnerTrie == self as *const ReInnerTrie).unwrap()
+  }
+
+  fn _static_kind(&self, eidx: usize) -> ReStaticKind {
+    if let Some(&assert) = self.asserts.get(&eidx) {
+      ReStaticKind::Assert(assert)
+    } else if let Some(&slot) = self.marks.get(&eidx) {
+      ReStaticKind::Mark(slot)
+    } else if self.klass.contains_value(eidx) {
+      ReStaticKind::Class
+    } else if self.bound1.contains_value(eidx) {
+      if self.lazy.contains_key(&eidx) { ReStaticKind::Bound1Lazy } else { ReStaticKind::Bound1 }
+    } else if self.unbound.contains_value(eidx) {
+      if self.lazy.contains_key(&eidx) { ReStaticKind::UnboundLazy } else { ReStaticKind::Unbound }
+    } else {
+      unreachable!()
+    }
+  }
+
+  fn _write_static<W: FmtWrite>(&self, id: usize, nodes: &[&ReInnerTrie], sw: &mut ReStaticWriter, w: &mut W) -> FmtResult {
+    writeln!(w, "    // {}", id)?;
+    writeln!(w, "    ReStaticNode{{")?;
+    writeln!(w, "      index: {:?},", self.index)?;
+    write!(w, "      lit_term: &[")?;
+    for (i, (c, r)) in self.lit_term.entries().into_iter().enumerate() {
+      if i % 6 == 0 {
+        write!(w, "\n        ")?;
+      } else {
+        write!(w, " ")?;
+      }
+      write!(w, "(")?;
+      write_static_char(c, w)?;
+      write!(w, ", {}),", r)?;
+    }
+    if !self.lit_term.rt.is_empty() {
+      write!(w, "\n      ")?;
+    }
+    writeln!(w, "],")?;
+    write!(w, "      literal: &[")?;
+    for (i, (&c, trie)) in self.literal.iter().enumerate() {
+      if i % 6 == 0 {
+        write!(w, "\n        ")?;
+      } else {
+        write!(w, " ")?;
+      }
+      write!(w, "(")?;
+      write_static_char(c, w)?;
+      write!(w, ", {}),", trie._static_id(nodes))?;
+    }
+    if !self.literal.is_empty() {
+      write!(w, "\n      ")?;
+    }
+    writeln!(w, "],")?;
+    write!(w, "      non_lit: &[")?;
+    for (eidx, r) in self.non_lit.iter().enumerate() {
+      let next = match r {
+        &ReInnerTrieRef::Terminal(ridx) => format!("ReStaticRef::Terminal({})", ridx),
+        &ReInnerTrieRef::Trie(ref trie) => format!("ReStaticRef::Trie({})", trie._static_id(nodes)),
+      };
+      let kind = match self._static_kind(eidx) {
+        // NB: written qualified, so that the generated `use` is the same
+        // whether or not any assertions appear.
+        ReStaticKind::Assert(assert) => format!("Assert(crate::re::ReAssert::{:?})", assert),
+        kind => format!("{:?}", kind),
+      };
+      write!(w, "\n        ReStaticEdge{{kind: ReStaticKind::{}, zero: {}, ranges: RANGES_{}, next: {}}},",
+          kind,
+          self.zero.contains(&eidx),
+          sw._ranges_id(self.ranges.get(&eidx).unwrap()),
+          next,
+      )?;
+    }
+    if !self.non_lit.is_empty() {
+      write!(w, "\n      ")?;
+    }
+    writeln!(w, "],")?;
+    writeln!(w, "    }},")
+  }
+
+  fn from_static(nodes: &[ReStaticNode], id: usize) -> ReInnerTrie {
+    let node = &nodes[id];
+    let mut trie = ReInnerTrie::default();
+    trie.index = node.index;
+    for &(c, r) in node.lit_term.iter() {
+      trie.lit_term.insert(c, r);
+    }
+    for &(c, n) in node.literal.iter() {
+      trie.literal.insert(c, ReInnerTrie::from_static(nodes, n));
+    }
+    for e in node.non_lit.iter() {
+      let next = match e.next {
+        ReStaticRef::Terminal(ridx) => ReInnerTrieRef::Terminal(ridx),
+        ReStaticRef::Trie(n) => ReInnerTrieRef::Trie(ReInnerTrie::from_static(nodes, n)),
+      };
+      trie._push_edge(e.kind, e.zero, e.ranges.to_vec(), next);
+    }
+    trie
+  }
+
+  fn _push_edge(&mut self, kind: ReStaticKind, zero: bool, ranges: Vec<(char, char)>, next: ReInnerTrieRef) {
+    self.ascii = ReAsciiCache::default();
+    let eidx = self.non_lit.len();
+    if zero {
+      self.zero.insert(eidx);
+    }
+    let map = match kind {
+      ReStaticKind::Class => Some(&mut self.klass),
+      ReStaticKind::Bound1 => Some(&mut self.bound1),
+      ReStaticKind::Unbound => Some(&mut self.unbound),
+      ReStaticKind::Assert(assert) => {
+        self.asserts.insert(eidx, assert);
+        None
+      }
+      ReStaticKind::Mark(slot) => {
+        self.marks.insert(eidx, slot);
+        None
+      }
+      ReStaticKind::Bound1Lazy => {
+        self.lazy.insert(eidx, Some(1));
+        Some(&mut self.bound1)
+      }
+      ReStaticKind::UnboundLazy => {
+        self.lazy.insert(eidx, None);
+        Some(&mut self.unbound)
+      }
+    };
+    if let Some(map) = map {
+      for &r in ranges.iter() {
+        map.insert(r, eidx);
+      }
+    }
+    self.ranges.insert(eidx, ranges);
+    self.non_lit.push(next);
+  }
+}
+
+/// Constructors of the rules of a serialized `ReTrie`, keyed by rule id.
+///
+/// A trie image stores only the ids of its rules; `ReTrie::from_image`
+/// looks up the map function of each rule here.
+pub struct ReRegistry<T> {
+  funs:     BTreeMap<SmolStr, ReFun<T>>,
+}
+
+impl<T> Default for ReRegistry<T> {
+  fn default() -> ReRegistry<T> {
+    ReRegistry{funs: BTreeMap::new()}
+  }
+}
+
+impl<T> ReRegistry<T> {
+  pub fn insert<K: Into<SmolStr>, F: 'static + Fn(&str) -> T>(&mut self, id: K, map_fun: F) {
+    self.funs.insert(id.into(), ReFun::Str(Rc::new(map_fun)));
+  }
+
+  /// Register a map function taking the match; see `ReTrie::push_match`.
+  pub fn insert_match<K: Into<SmolStr>, F: 'static + Fn(&ReMatch) -> T>(&mut self, id: K, map_fun: F) {
+    self.funs.insert(id.into(), ReFun::Match(Rc::new(map_fun)));
+  }
+
+  pub fn contains(&self, id: &str) -> bool {
+    self.funs.contains_key(id)
+  }
+
+  pub fn len(&self) -> usize {
+    self.funs.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.funs.is_empty()
+  }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ReImageError {
+  /// The input does not start with `RE_IMAGE_MAGIC`.
+  BadMagic,
+  /// The image was written by an unsupported format version.
+  BadVersion(u32),
+  /// The input ended in the middle of the image.
+  Truncated,
+  /// The image is inconsistent (e.g. a node or rule index is out of
+  /// bounds), at the given byte offset.
+  Malformed(usize),
+  /// A rule id of the image is missing from the registry.
+  UnknownRule(SmolStr),
+}
+
+pub const RE_IMAGE_MAGIC: &'static [u8; 4] = b"CPRT";
+pub const RE_IMAGE_VERSION: u32 = 2;
+
+const RE_IMAGE_NONE: u32 = u32::max_value();
+
+impl ReRuleTrie {
+  /// Serialize the trie to a binary image, identifying its rules (in
+  /// priority order) by `rule_ids`.
+  ///
+  /// The format is stable across versions with the same `RE_IMAGE_VERSION`.
+  /// All integers are little-endian `u32`s, and chars are their scalar
+  /// values:
+  ///
+  /// ```text
+  /// image   = magic version nrules rule* nnodes node*
+  /// rule    = id nnames (group name)*
+  /// id      = len utf8-bytes
+  /// name    = len utf8-bytes
+  /// node    = index (NONE if absent)
+  ///           nlit_term (char rule)*
+  ///           nliteral (char node)*
+  ///           nnon_lit edge*
+  /// edge    = kind (0 class, 1 bound1, 2 unbound, 3 assert,
+  ///                 4 lazy bound1, 5 lazy unbound, 6 mark)
+  ///           [assert (index in RE_ASSERTS), if kind is 3]
+  ///           [slot (2 * group, or 2 * group + 1), if kind is 6]
+  ///           zero (0 or 1)
+  ///           nranges (lb ub)*
+  ///           next_kind (0 terminal, 1 trie) next (rule or node)
+  /// ```
+  ///
+  /// Nodes are in preorder, with node 0 the root.
+  pub fn to_image(&self, rule_ids: &[&str]) -> Vec<u8> {
+    assert_eq!(rule_ids.len(), self.nrules, "bug: ReRuleTrie::to_image: rule count mismatch");
+    let mut nodes = Vec::new();
+    self.inner._collect_static(&mut nodes);
+    let mut buf = Vec::new();
+    buf.extend_from_slice(RE_IMAGE_MAGIC);
+    put_u32(&mut buf, RE_IMAGE_VERSION);
+    put_u32(&mut buf, rule_ids.len() as u32);
+    for (ridx, id) in rule_ids.iter().enumerate() {
+      put_u32(&mut buf, id.len() as u32);
+      buf.extend_from_slice(id.as_bytes());
+      let names = self.names.get(&ridx).map(|names| &names[ .. ]).unwrap_or(&[]);
+      put_u32(&mut buf, names.len() as u32);
+      for &(group, ref name) in names.iter() {
+        put_u32(&mut buf, group as u32);
+        put_u32(&mut buf, name.len() as u32);
+        buf.extend_from_slice(name.as_bytes());
+      }
+    }
+    put_u32(&mut buf, nodes.len() as u32);
+    for node in nodes.iter() {
+      node._write_image(&nodes, &mut buf);
+    }
+    buf
+  }
+
+  /// Load a trie from a binary image (see `to_image`), together with the
+  /// ids of its rules.
+  pub fn from_image(image: &[u8]) -> Result<(ReRuleTrie, Vec<SmolStr>), ReImageError> {
+    let mut r = ReImageReader{buf: image, off: 0};
+    if r.bytes(RE_IMAGE_MAGIC.len())? != &RE_IMAGE_MAGIC[ .. ] {
+      return Err(ReImageError::BadMagic);
+    }
+    let version = r.u32()?;
+    if version != RE_IMAGE_VERSION {
+      return Err(ReImageError::BadVersion(version));
+    }
+    let nrules = r.u32()? as usize;
+    let mut rule_ids = Vec::new();
+    let mut names = BTreeMap::new();
+    for ridx in 0 .. nrules {
+      rule_ids.push(r.str()?.into());
+      let mut rule_names = Vec::new();
+      for _ in 0 .. r.count()? {
+        let group = r.u32()? as usize;
+        rule_names.push((group, r.str()?.into()));
+      }
+      if !rule_names.is_empty() {
+        names.insert(ridx, rule_names);
+      }
+    }
+    let nnodes = r.u32()? as usize;
+    let mut nodes = Vec::new();
+    for id in 0 .. nnodes {
+      nodes.push(ReImageNode::read(&mut r, id, nnodes, nrules)?);
+    }
+    if nodes.is_empty() || r.off != image.len() {
+      return Err(ReImageError::Malformed(r.off));
+    }
+    // NB: the nodes must form a tree rooted at node 0.
+    let mut nrefs = vec![0; nnodes];
+    for node in nodes.iter() {
+      for &(_, n) in node.literal.iter() {
+        nrefs[n] += 1;
+      }
+      for e in node.non_lit.iter() {
+        if let ReStaticRef::Trie(n) = e.next {
+          nrefs[n] += 1;
+        }
+      }
     }
-    for ridx in self.lit_term.values(c) {
-      match (ctx, mat) {
-        (None, None) => {
-          //println!("TRACE: re:   literal: terminal N N ({})", ridx);
-          mat = Some((c_len, ridx));
+    if nrefs[1 .. ].iter().any(|&k| k != 1) {
+      return Err(ReImageError::Malformed(r.off));
+    }
+    let trie = ReRuleTrie{
+      inner:    ReInnerTrie::from_image(&mut nodes, 0),
+      nrules,
+      names,
+      ..ReRuleTrie::default()
+    };
+    Ok((trie, rule_ids))
+  }
+
+  /// Write the trie as JSON, for inspection. The structure mirrors the
+  /// binary image (see `to_image`); loading is only supported from the
+  /// binary image.
+  pub fn write_json<W: FmtWrite>(&self, rule_ids: &[&str], w: &mut W) -> FmtResult {
+    assert_eq!(rule_ids.len(), self.nrules, "bug: ReRuleTrie::write_json: rule count mismatch");
+    let mut nodes = Vec::new();
+    self.inner._collect_static(&mut nodes);
+    write!(w, "{{\"version\":{},\"rules\":[", RE_IMAGE_VERSION)?;
+    for (i, id) in rule_ids.iter().enumerate() {
+      if i > 0 {
+        write!(w, ",")?;
+      }
+      write_json_str(id, w)?;
+    }
+    write!(w, "],\"names\":[")?;
+    let names = self.names.iter().flat_map(|(&ridx, names)| names.iter().map(move |n| (ridx, n)));
+    for (i, (ridx, &(group, ref name))) in names.enumerate() {
+      if i > 0 {
+        write!(w, ",")?;
+      }
+      write!(w, "[{},{},", ridx, group)?;
+      write_json_str(name, w)?;
+      write!(w, "]")?;
+    }
+    write!(w, "],\"nodes\":[")?;
+    for (id, node) in nodes.iter().enumerate() {
+      if id > 0 {
+        write!(w, ",")?;
+      }
+      node._write_json(&nodes, w)?;
+    }
+    write!(w, "]}}")
+  }
+}
+
+impl<T> ReTrie<T> {
+  /// Serialize the trie to a binary image; see `ReRuleTrie::to_image`.
+  pub fn to_image(&self, rule_ids: &[&str]) -> Vec<u8> {
+    self.rules.to_image(rule_ids)
+  }
+
+  /// Load a trie from a binary image (see `to_image`), taking the map
+  /// function of each rule from `registry`.
+  pub fn from_image(image: &[u8], registry: &ReRegistry<T>) -> Result<ReTrie<T>, ReImageError> {
+    let (rules, rule_ids) = ReRuleTrie::from_image(image)?;
+    let mut map_funs = Vec::new();
+    for id in rule_ids.into_iter() {
+      match registry.funs.get(&id) {
+        None => return Err(ReImageError::UnknownRule(id)),
+        Some(f) => map_funs.push(f.clone())
+      }
+    }
+    Ok(ReTrie{rules, map_funs})
+  }
+
+  pub fn write_json<W: FmtWrite>(&self, rule_ids: &[&str], w: &mut W) -> FmtResult {
+    self.rules.write_json(rule_ids, w)
+  }
+
+  /// Analyze the rules for conflicts; see `ReRuleTrie::diagnose`.
+  pub fn diagnose(&self) -> Vec<ReDiagnostic> {
+    self.rules.diagnose()
+  }
+}
+
+fn write_json_str<W: FmtWrite>(s: &str, w: &mut W) -> FmtResult {
+  w.write_char('"')?;
+  for c in s.chars() {
+    match c {
+      '"' => w.write_str("\\\"")?,
+      '\\' => w.write_str("\\\\")?,
+      c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32)?,
+      c => w.write_char(c)?
+    }
+  }
+  w.write_char('"')
+}
+
+fn put_u32(buf: &mut Vec<u8>, x: u32) {
+  buf.extend_from_slice(&x.to_le_bytes());
+}
+
+struct ReImageReader<'a> {
+  buf:  &'a [u8],
+  off:  usize,
+}
+
+impl<'a> ReImageReader<'a> {
+  fn bytes(&mut self, len: usize) -> Result<&'a [u8], ReImageError> {
+    if self.buf.len() - self.off < len {
+      return Err(ReImageError::Truncated);
+    }
+    let b = &self.buf[self.off .. self.off + len];
+    self.off += len;
+    Ok(b)
+  }
+
+  fn u32(&mut self) -> Result<u32, ReImageError> {
+    let b = self.bytes(4)?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+  }
+
+  fn index(&mut self, bound: usize) -> Result<usize, ReImageError> {
+    let off = self.off;
+    let x = self.u32()? as usize;
+    if x >= bound {
+      return Err(ReImageError::Malformed(off));
+    }
+    Ok(x)
+  }
+
+  fn str(&mut self) -> Result<&'a str, ReImageError> {
+    let len = self.u32()? as usize;
+    let off = self.off;
+    match from_utf8(self.bytes(len)?) {
+      Err(_) => Err(ReImageError::Malformed(off)),
+      Ok(s) => Ok(s)
+    }
+  }
+
+  fn char(&mut self) -> Result<char, ReImageError> {
+    let off = self.off;
+    match char::from_u32(self.u32()?) {
+      None => Err(ReImageError::Malformed(off)),
+      Some(c) => Ok(c)
+    }
+  }
+
+  // NB: child nodes always follow their parent in preorder, which also
+  // rules out cycles.
+  fn child(&mut self, id: usize, nnodes: usize) -> Result<usize, ReImageError> {
+    let off = self.off;
+    let n = self.index(nnodes)?;
+    if n <= id {
+      return Err(ReImageError::Malformed(off));
+    }
+    Ok(n)
+  }
+
+  fn count(&mut self) -> Result<usize, ReImageError> {
+    let n = self.u32()? as usize;
+    // NB: every entry takes at least 4 bytes.
+    if (self.buf.len() - self.off) / 4 < n {
+      return Err(ReImageError::Truncated);
+    }
+    Ok(n)
+  }
+}
+
+struct ReImageEdge {
+  kind:     ReStaticKind,
+  zero:     bool,
+  ranges:   Vec<(char, char)>,
+  next:     ReStaticRef,
+}
+
+#[derive(Default)]
+struct ReImageNode {
+  index:    Option<usize>,
+  lit_term: Vec<(char, usize)>,
+  literal:  Vec<(char, usize)>,
+  non_lit:  Vec<ReImageEdge>,
+}
+
+impl ReImageNode {
+  fn read(r: &mut ReImageReader, id: usize, nnodes: usize, nrules: usize) -> Result<ReImageNode, ReImageError> {
+    let mut node = ReImageNode::default();
+    let off = r.off;
+    node.index = match r.u32()? {
+      RE_IMAGE_NONE => None,
+      x if (x as usize) < nrules => Some(x as usize),
+      _ => return Err(ReImageError::Malformed(off))
+    };
+    for _ in 0 .. r.count()? {
+      let c = r.char()?;
+      node.lit_term.push((c, r.index(nrules)?));
+    }
+    for _ in 0 .. r.count()? {
+      let c = r.char()?;
+      node.literal.push((c, r.child(id, nnodes)?));
+    }
+    for _ in 0 .. r.count()? {
+      let off = r.off;
+      let kind = match r.u32()? {
+        0 => ReStaticKind::Class,
+        1 => ReStaticKind::Bound1,
+        2 => ReStaticKind::Unbound,
+        3 => ReStaticKind::Assert(RE_ASSERTS[r.index(RE_ASSERTS.len())?]),
+        4 => ReStaticKind::Bound1Lazy,
+        5 => ReStaticKind::UnboundLazy,
+        // NB: each group takes two mark edges, so that its slots are less
+        // than the image length.
+        6 => ReStaticKind::Mark(r.index(r.buf.len())?),
+        _ => return Err(ReImageError::Malformed(off))
+      };
+      let off = r.off;
+      let zero = match r.u32()? {
+        0 => false,
+        1 => true,
+        _ => return Err(ReImageError::Malformed(off))
+      };
+      let mut ranges: Vec<(char, char)> = Vec::new();
+      for _ in 0 .. r.count()? {
+        let off = r.off;
+        let lb = r.char()?;
+        let ub = r.char()?;
+        // NB: ranges are sorted and disjoint, as the range indexes of the
+        // trie assume.
+        if lb > ub || ranges.last().map_or(false, |&(_, prev_ub)| prev_ub >= lb) {
+          return Err(ReImageError::Malformed(off));
         }
-        (None, Some((old_len, old_idx))) => if old_len < c_len
-            || old_len == c_len && ridx < old_idx {
-          //println!("TRACE: re:   literal: terminal N S ({})", ridx);
-          mat = Some((c_len, ridx));
+        ranges.push((lb, ub));
+      }
+      match kind {
+        ReStaticKind::Class |
+        ReStaticKind::Bound1 |
+        ReStaticKind::Unbound |
+        ReStaticKind::Bound1Lazy |
+        ReStaticKind::UnboundLazy => if ranges.is_empty() {
+          return Err(ReImageError::Malformed(off));
+        },
+        ReStaticKind::Assert(_) |
+        ReStaticKind::Mark(_) => {}
+      }
+      let off = r.off;
+      let next = match r.u32()? {
+        0 => ReStaticRef::Terminal(r.index(nrules)?),
+        1 => ReStaticRef::Trie(r.child(id, nnodes)?),
+        _ => return Err(ReImageError::Malformed(off))
+      };
+      node.non_lit.push(ReImageEdge{kind, zero, ranges, next});
+    }
+    Ok(node)
+  }
+}
+
+impl ReInnerTrie {
+  fn _write_image(&self, nodes: &[&ReInnerTrie], buf: &mut Vec<u8>) {
+    put_u32(buf, self.index.map(|x| x as u32).unwrap_or(RE_IMAGE_NONE));
+    let lit_term = self.lit_term.entries();
+    put_u32(buf, lit_term.len() as u32);
+    for (c, r) in lit_term.into_iter() {
+      put_u32(buf, c as u32);
+      put_u32(buf, r as u32);
+    }
+    put_u32(buf, self.literal.len() as u32);
+    for (&c, trie) in self.literal.iter() {
+      put_u32(buf, c as u32);
+      put_u32(buf, trie._static_id(nodes) as u32);
+    }
+    put_u32(buf, self.non_lit.len() as u32);
+    for (eidx, r) in self.non_lit.iter().enumerate() {
+      match self._static_kind(eidx) {
+        ReStaticKind::Class => put_u32(buf, 0),
+        ReStaticKind::Bound1 => put_u32(buf, 1),
+        ReStaticKind::Unbound => put_u32(buf, 2),
+        ReStaticKind::Assert(assert) => {
+          put_u32(buf, 3);
+          put_u32(buf, RE_ASSERTS.iter().position(|&a| a == assert).unwrap() as u32);
         }
-        (Some(ctx), _) => if ctx == ridx {
-          //println!("TRACE: re:   literal: terminal S _ ({})", ridx);
-          mat = Some((c_len, ridx));
-          return mat;
+        ReStaticKind::Bound1Lazy => put_u32(buf, 4),
+        ReStaticKind::UnboundLazy => put_u32(buf, 5),
+        ReStaticKind::Mark(slot) => {
+          put_u32(buf, 6);
+          put_u32(buf, slot as u32);
+        }
+      }
+      put_u32(buf, self.zero.contains(&eidx) as u32);
+      let ranges = self.ranges.get(&eidx).unwrap();
+      put_u32(buf, ranges.len() as u32);
+      for &(lb, ub) in ranges.iter() {
+        put_u32(buf, lb as u32);
+        put_u32(buf, ub as u32);
+      }
+      match r {
+        &ReInnerTrieRef::Terminal(ridx) => {
+          put_u32(buf, 0);
+          put_u32(buf, ridx as u32);
+        }
+        &ReInnerTrieRef::Trie(ref trie) => {
+          put_u32(buf, 1);
+          put_u32(buf, trie._static_id(nodes) as u32);
         }
       }
     }
-    if let Some(trie) = self.literal.get(&c) {
-      match trie._match(suffix, ctx) {
-        None => {
-          //println!("TRACE: re:   literal: no trie match");
+  }
+
+  fn _write_json<W: FmtWrite>(&self, nodes: &[&ReInnerTrie], w: &mut W) -> FmtResult {
+    match self.index {
+      None => write!(w, "{{\"index\":null,\"lit_term\":[")?,
+      Some(x) => write!(w, "{{\"index\":{},\"lit_term\":[", x)?
+    }
+    for (i, (c, r)) in self.lit_term.entries().into_iter().enumerate() {
+      if i > 0 {
+        write!(w, ",")?;
+      }
+      write!(w, "[{},{}]", c as u32, r)?;
+    }
+    write!(w, "],\"literal\":[")?;
+    for (i, (&c, trie)) in self.literal.iter().enumerate() {
+      if i > 0 {
+        write!(w, ",")?;
+      }
+      write!(w, "[{},{}]", c as u32, trie._static_id(nodes))?;
+    }
+    write!(w, "],\"non_lit\":[")?;
+    for (eidx, r) in self.non_lit.iter().enumerate() {
+      if eidx > 0 {
+        write!(w, ",")?;
+      }
+      write!(w, "{{\"kind\":\"{:?}\",\"zero\":{},\"ranges\":[",
+          self._static_kind(eidx),
+          self.zero.contains(&eidx),
+      )?;
+      for (i, &(lb, ub)) in self.ranges.get(&eidx).unwrap().iter().enumerate() {
+        if i > 0 {
+          write!(w, ",")?;
         }
-        Some((suffix_len, ridx)) => match (ctx, mat) {
-          (None, None) => {
-            //println!("TRACE: re:   literal: trie N N ({})", ridx);
-            mat = Some((c_len + suffix_len, ridx));
-          }
-          (None, Some((old_len, old_idx))) => if old_len < c_len + suffix_len
-              || old_len == c_len + suffix_len && ridx < old_idx {
-            //println!("TRACE: re:   literal: trie N S ({})", ridx);
-            mat = Some((c_len + suffix_len, ridx));
-          }
-          (Some(ctx), _) => if ctx == ridx {
-            //println!("TRACE: re:   literal: trie S _ ({})", ridx);
-            mat = Some((c_len + suffix_len, ridx));
-            return mat;
-          }
+        write!(w, "[{},{}]", lb as u32, ub as u32)?;
+      }
+      match r {
+        &ReInnerTrieRef::Terminal(ridx) => write!(w, "],\"terminal\":{}}}", ridx)?,
+        &ReInnerTrieRef::Trie(ref trie) => write!(w, "],\"trie\":{}}}", trie._static_id(nodes))?,
+      }
+    }
+    write!(w, "]}}")
+  }
+
+  fn from_image(nodes: &mut [ReImageNode], id: usize) -> ReInnerTrie {
+    let node = std::mem::replace(&mut nodes[id], ReImageNode::default());
+    let mut trie = ReInnerTrie::default();
+    trie.index = node.index;
+    for (c, r) in node.lit_term.into_iter() {
+      trie.lit_term.insert(c, r);
+    }
+    for (c, n) in node.literal.into_iter() {
+      trie.literal.insert(c, ReInnerTrie::from_image(nodes, n));
+    }
+    for e in node.non_lit.into_iter() {
+      let next = match e.next {
+        ReStaticRef::Terminal(ridx) => ReInnerTrieRef::Terminal(ridx),
+        ReStaticRef::Trie(n) => ReInnerTrieRef::Trie(ReInnerTrie::from_image(nodes, n)),
+      };
+      trie._push_edge(e.kind, e.zero, e.ranges, next);
+    }
+    trie
+  }
+}
+
+/// A finding of `ReTrie::diagnose`. Rules are given by their index.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ReDiagnostic {
+  /// `rule` and the earlier rule `other` both match all of `example`, and
+  /// `other` wins the tie.
+  Overlap{rule: usize, other: usize, example: String},
+  /// Every string matched by `rule` is also matched by an earlier rule, so
+  /// `rule` never wins; e.g. `example`, the shortest string of `rule`, goes
+  /// to `other`.
+  Unreachable{rule: usize, other: usize, example: String},
+  /// `rule` matches the empty string.
+  EmptyMatch{rule: usize},
+}
+
+/// One step of the (linear) pattern of a rule, for `ReTrie::diagnose` and
+/// for finding capture groups. `Opt` and `Star` are lazy if their flag is
+/// set; `Mark` is a capture group bound, which consumes no chars.
+#[derive(Clone, Debug)]
+enum ReStep {
+  One(Vec<(char, char)>),
+  Opt(Vec<(char, char)>, bool),
+  Star(Vec<(char, char)>, bool),
+  Mark(usize),
+}
+
+impl ReStep {
+  fn ranges(&self) -> &[(char, char)] {
+    match self {
+      &ReStep::One(ref ranges) |
+      &ReStep::Opt(ref ranges, _) |
+      &ReStep::Star(ref ranges, _) => ranges,
+      &ReStep::Mark(_) => &[],
+    }
+  }
+}
+
+impl ReRuleTrie {
+  /// Analyze the rules for conflicts: pairs of rules that match the same
+  /// string, rules that can never win, and rules matching the empty string.
+  ///
+  /// The analysis is on the strings matched by each pattern on its own, as
+  /// if repetitions could backtrack; assertions are not taken into account.
+  pub fn diagnose(&self) -> Vec<ReDiagnostic> {
+    let paths = self._paths();
+    let mut diags = Vec::new();
+    for (rule, path) in paths.iter().enumerate() {
+      if re_step_closure(path, &[0]).contains(&path.len()) {
+        diags.push(ReDiagnostic::EmptyMatch{rule});
+      }
+    }
+    for rule in 0 .. paths.len() {
+      for other in 0 .. rule {
+        if let Some(example) = re_paths_overlap(&paths[other], &paths[rule]) {
+          diags.push(ReDiagnostic::Overlap{rule, other, example});
         }
       }
     }
-    for (lb, ub) in self.klass.keys() {
-      if ub < c {
-        continue;
-      } else if c < lb {
-        break;
+    for rule in 1 .. paths.len() {
+      if let Some((other, example)) = re_path_shadowed(&paths, rule) {
+        diags.push(ReDiagnostic::Unreachable{rule, other, example});
       }
-      for eidx in self.klass.values((lb, ub)) {
-        match &self.non_lit[eidx] {
-          &ReInnerTrieRef::Terminal(ridx) => match (ctx, mat) {
-            (None, None) => {
-              //println!("TRACE: re:   klass: terminal N N");
-              mat = Some((c_len, ridx));
-            }
-            (None, Some((old_len, old_idx))) => if old_len < c_len
-                || old_len == c_len && ridx < old_idx {
-              //println!("TRACE: re:   klass: terminal N S");
-              mat = Some((c_len, ridx));
-            }
-            (Some(ctx), _) => if ctx == ridx {
-              //println!("TRACE: re:   klass: terminal S _");
-              mat = Some((c_len, ridx));
-              return mat;
-            }
-          }
-          &ReInnerTrieRef::Trie(ref trie) => match trie._match(suffix, ctx) {
-            None => {
-              //println!("TRACE: re:   klass: no trie match");
-            }
-            Some((suffix_len, ridx)) => match (ctx, mat) {
-              (None, None) => {
-                //println!("TRACE: re:   klass: trie N N");
-                mat = Some((c_len + suffix_len, ridx));
-              }
-              (None, Some((old_len, old_idx))) => if old_len < c_len + suffix_len
-                  || old_len == c_len + suffix_len && ridx < old_idx {
-                //println!("TRACE: re:   klass: trie N S");
-                mat = Some((c_len + suffix_len, ridx));
-              }
-              (Some(ctx), _) => if ctx == ridx {
-                //println!("TRACE: re:   klass: trie S _");
-                mat = Some((c_len + suffix_len, ridx));
-                return mat;
-              }
-            }
+    }
+    diags
+  }
+}
+
+impl ReInnerTrie {
+  fn _collect_paths(&self, path: &mut Vec<ReStep>, paths: &mut [Option<Vec<ReStep>>]) {
+    for (c, ridx) in self.lit_term.entries().into_iter() {
+      let mut p = path.clone();
+      p.push(ReStep::One(vec![(c, c)]));
+      paths[ridx] = Some(p);
+    }
+    for (&c, trie) in self.literal.iter() {
+      path.push(ReStep::One(vec![(c, c)]));
+      trie._collect_paths(path, paths);
+      path.pop();
+    }
+    for (eidx, r) in self.non_lit.iter().enumerate() {
+      let ranges = self.ranges.get(&eidx).unwrap().clone();
+      let zero = self.zero.contains(&eidx);
+      let lazy = self.lazy.contains_key(&eidx);
+      let depth = path.len();
+      match self._static_kind(eidx) {
+        ReStaticKind::Class => path.push(ReStep::One(ranges)),
+        ReStaticKind::Bound1 | ReStaticKind::Bound1Lazy => if zero {
+          path.push(ReStep::Opt(ranges, lazy));
+        } else {
+          path.push(ReStep::One(ranges));
+        },
+        ReStaticKind::Unbound | ReStaticKind::UnboundLazy => {
+          if !zero {
+            path.push(ReStep::One(ranges.clone()));
           }
+          path.push(ReStep::Star(ranges, lazy));
+        }
+        ReStaticKind::Assert(_) => {}
+        ReStaticKind::Mark(slot) => path.push(ReStep::Mark(slot)),
+      }
+      match r {
+        &ReInnerTrieRef::Terminal(ridx) => {
+          paths[ridx] = Some(path.clone());
+        }
+        &ReInnerTrieRef::Trie(ref trie) => {
+          trie._collect_paths(path, paths);
         }
       }
+      path.truncate(depth);
     }
-    for (lb, ub) in self.bound1.keys() {
-      if ub < c {
-        continue;
-      } else if c < lb {
+  }
+}
+
+/// The positions in `path` reachable from `pos` without consuming a char.
+fn re_step_closure(path: &[ReStep], pos: &[usize]) -> Vec<usize> {
+  let mut closure = Vec::new();
+  for &p in pos.iter() {
+    let mut p = p;
+    loop {
+      if !closure.contains(&p) {
+        closure.push(p);
+      }
+      match path.get(p) {
+        Some(&ReStep::Opt(..)) | Some(&ReStep::Star(..)) | Some(&ReStep::Mark(_)) => p += 1,
+        _ => break
+      }
+    }
+  }
+  closure.sort();
+  closure
+}
+
+/// The positions in `path` reachable from `pos` by consuming `c`.
+fn re_step_next(path: &[ReStep], pos: &[usize], c: char) -> Vec<usize> {
+  let mut next = Vec::new();
+  for &p in pos.iter() {
+    match path.get(p) {
+      None => {}
+      Some(&ReStep::Star(ref ranges, _)) => if ranges_contain(ranges, c) {
+        next.push(p);
+      },
+      Some(step) => if ranges_contain(step.ranges(), c) {
+        next.push(p + 1);
+      }
+    }
+  }
+  re_step_closure(path, &next)
+}
+
+/// Match all of `text[off .. ]` against `path`, setting the capture group
+/// slots of its `Mark` steps.
+fn re_steps_captures(path: &[ReStep], text: &str, off: usize, slots: &mut [Option<usize>]) -> bool {
+  let (step, rest) = match path.split_first() {
+    None => return off == text.len(),
+    Some(s) => s
+  };
+  let rem = &text[off .. ];
+  match step {
+    &ReStep::Mark(slot) => {
+      let old = slots[slot];
+      slots[slot] = Some(off);
+      if re_steps_captures(rest, text, off, slots) {
+        return true;
+      }
+      slots[slot] = old;
+      false
+    }
+    &ReStep::One(ref ranges) => match rem.chars().next() {
+      Some(c) if ranges_contain(ranges, c) => {
+        re_steps_captures(rest, text, off + c.len_utf8(), slots)
+      }
+      _ => false
+    },
+    &ReStep::Opt(ref ranges, lazy) | &ReStep::Star(ref ranges, lazy) => {
+      let max = match step {
+        &ReStep::Opt(..) => 1,
+        _ => usize::max_value()
+      };
+      let mut ends = vec![off];
+      for c in rem.chars().take(max) {
+        if !ranges_contain(ranges, c) {
+          break;
+        }
+        ends.push(ends[ends.len() - 1] + c.len_utf8());
+      }
+      if !lazy {
+        ends.reverse();
+      }
+      ends.into_iter().any(|end| re_steps_captures(rest, text, end, slots))
+    }
+  }
+}
+
+/// Split `ranges` at the bounds of `cuts`, so that each of the resulting
+/// ranges is either inside or outside of each of the `cuts`.
+fn re_split_ranges(ranges: &[(char, char)], cuts: &[(char, char)]) -> Vec<(char, char)> {
+  let mut bounds: Vec<u32> = Vec::new();
+  for &(lb, ub) in cuts.iter() {
+    bounds.push(lb as u32);
+    bounds.push(ub as u32 + 1);
+  }
+  bounds.sort();
+  bounds.dedup();
+  let mut split = Vec::new();
+  let mut push = |lb: u32, ub: u32| {
+    // NB: skip over the surrogates, which may fall within a range.
+    let lb = if lb >= 0xd800 && lb < 0xe000 { 0xe000 } else { lb };
+    let ub = if ub >= 0xd800 && ub < 0xe000 { 0xd7ff } else { ub };
+    if lb <= ub {
+      split.push((char::from_u32(lb).unwrap(), char::from_u32(ub).unwrap()));
+    }
+  };
+  for &(lb, ub) in ranges.iter() {
+    let mut lb = lb as u32;
+    let ub = ub as u32;
+    let start = match bounds.binary_search(&(lb + 1)) {
+      Ok(i) => i,
+      Err(i) => i
+    };
+    for &b in bounds[start .. ].iter() {
+      if b > ub {
         break;
       }
-      for eidx in self.bound1.values((lb, ub)) {
-        match &self.non_lit[eidx] {
-          &ReInnerTrieRef::Terminal(ridx) => match (ctx, mat) {
-            (None, None) => {
-              //println!("TRACE: re:   bound1: terminal N N");
-              mat = Some((c_len, ridx));
-            }
-            (None, Some((old_len, old_idx))) => if old_len < c_len
-                || old_len == c_len && ridx < old_idx {
-              //println!("TRACE: re:   bound1: terminal N S");
-              mat = Some((c_len, ridx));
-            }
-            (Some(ctx), _) => if ctx == ridx {
-              //println!("TRACE: re:   bound1: terminal S _");
-              mat = Some((c_len, ridx));
-              return mat;
-            }
-          }
-          &ReInnerTrieRef::Trie(ref trie) => match trie._match(suffix, ctx) {
-            None => {
-              //println!("TRACE: re:   bound1: no trie match");
-            }
-            Some((suffix_len, ridx)) => match (ctx, mat) {
-              (None, None) => {
-                //println!("TRACE: re:   bound1: trie N N");
-                mat = Some((c_len + suffix_len, ridx));
-              }
-              (None, Some((old_len, old_idx))) => if old_len < c_len + suffix_len
-                  || old_len == c_len + suffix_len && ridx < old_idx {
-                //println!("TRACE: re:   bound1: trie N S");
-                mat = Some((c_len + suffix_len, ridx));
-              }
-              (Some(ctx), _) => if ctx == ridx {
-                //println!("TRACE: re:   bound1: trie S _");
-                mat = Some((c_len + suffix_len, ridx));
-                return mat;
-              }
-            }
-          }
+      push(lb, b - 1);
+      lb = b;
+    }
+    push(lb, ub);
+  }
+  split
+}
+
+/// The shortest string matched by both `lpath` and `rpath`, if any.
+fn re_paths_overlap(lpath: &[ReStep], rpath: &[ReStep]) -> Option<String> {
+  let start = (re_step_closure(lpath, &[0]), re_step_closure(rpath, &[0]));
+  let mut seen = BTreeMap::new();
+  seen.insert(start.clone(), None);
+  let mut queue = vec![start];
+  let mut head = 0;
+  while head < queue.len() {
+    let (lpos, rpos) = queue[head].clone();
+    head += 1;
+    if lpos.contains(&lpath.len()) && rpos.contains(&rpath.len()) {
+      return Some(re_example(&seen, (lpos, rpos)));
+    }
+    let mut ranges: Vec<(char, char)> = Vec::new();
+    let mut cuts: Vec<(char, char)> = Vec::new();
+    for &p in rpos.iter() {
+      if let Some(step) = rpath.get(p) {
+        ranges.extend_from_slice(step.ranges());
+        cuts.extend_from_slice(step.ranges());
+      }
+    }
+    for &p in lpos.iter() {
+      if let Some(step) = lpath.get(p) {
+        cuts.extend_from_slice(step.ranges());
+      }
+    }
+    for (c, _) in re_split_ranges(&ranges, &cuts).into_iter() {
+      let lnext = re_step_next(lpath, &lpos, c);
+      let rnext = re_step_next(rpath, &rpos, c);
+      if lnext.is_empty() || rnext.is_empty() {
+        continue;
+      }
+      let next = (lnext, rnext);
+      if !seen.contains_key(&next) {
+        seen.insert(next.clone(), Some(((lpos.clone(), rpos.clone()), c)));
+        queue.push(next);
+      }
+    }
+  }
+  None
+}
+
+/// If every string of `paths[rule]` is matched by an earlier rule, the
+/// earlier rule matching the shortest string of `paths[rule]`, and that
+/// string.
+fn re_path_shadowed(paths: &[Vec<ReStep>], rule: usize) -> Option<(usize, String)> {
+  let path = &paths[rule];
+  // NB: the state of the earlier rules is the set of (rule, position) pairs.
+  let mut others = Vec::new();
+  for other in 0 .. rule {
+    for p in re_step_closure(&paths[other], &[0]).into_iter() {
+      others.push((other, p));
+    }
+  }
+  let start = (re_step_closure(path, &[0]), others);
+  let mut seen = BTreeMap::new();
+  seen.insert(start.clone(), None);
+  let mut queue = vec![start];
+  let mut head = 0;
+  let mut shortest = None;
+  while head < queue.len() {
+    let (pos, others) = queue[head].clone();
+    head += 1;
+    if pos.contains(&path.len()) {
+      match others.iter().find(|&&(other, p)| p == paths[other].len()) {
+        None => return None,
+        Some(&(other, _)) => if shortest.is_none() {
+          shortest = Some((other, re_example(&seen, (pos.clone(), others.clone()))));
         }
       }
     }
-    for (lb, ub) in self.unbound.keys() {
-      if ub < c {
+    let mut ranges: Vec<(char, char)> = Vec::new();
+    let mut cuts: Vec<(char, char)> = Vec::new();
+    for &p in pos.iter() {
+      if let Some(step) = path.get(p) {
+        ranges.extend_from_slice(step.ranges());
+        cuts.extend_from_slice(step.ranges());
+      }
+    }
+    for &(other, p) in others.iter() {
+      if let Some(step) = paths[other].get(p) {
+        cuts.extend_from_slice(step.ranges());
+      }
+    }
+    for (c, _) in re_split_ranges(&ranges, &cuts).into_iter() {
+      let next_pos = re_step_next(path, &pos, c);
+      if next_pos.is_empty() {
         continue;
-      } else if c < lb {
-        break;
       }
-      for eidx in self.unbound.values((lb, ub)) {
-        let mut rep_len = c_len;
-        let mut suffix = suffix;
-        loop {
-          let c = match suffix.chars().next() {
-            None => break,
-            Some(c) => c
-          };
-          let c_len = len_utf8(c as _);
-          let mut more = false;
-          for &(lb, ub) in self.ranges.get(&eidx).unwrap().iter() {
-            if lb <= c && c <= ub {
-              more = true;
-              break;
-            }
-          }
-          if !more {
-            break;
-          }
-          rep_len += c_len;
-          suffix = suffix.get(c_len .. ).unwrap();
+      let mut next_others = Vec::new();
+      for other in 0 .. rule {
+        let opos: Vec<_> = others.iter().filter(|&&(o, _)| o == other).map(|&(_, p)| p).collect();
+        if opos.is_empty() {
+          continue;
         }
-        match &self.non_lit[eidx] {
-          &ReInnerTrieRef::Terminal(ridx) => match (ctx, mat) {
-            (None, None) => {
-              //println!("TRACE: re:   repeat: terminal N N");
-              mat = Some((rep_len, ridx));
-            }
-            (None, Some((old_len, old_idx))) => if old_len < rep_len
-                || old_len == rep_len && ridx < old_idx {
-              //println!("TRACE: re:   repeat: terminal N S");
-              mat = Some((rep_len, ridx));
-            }
-            (Some(ctx), _) => if ctx == ridx {
-              //println!("TRACE: re:   repeat: terminal S _");
-              mat = Some((rep_len, ridx));
-              return mat;
-            }
-          }
-          &ReInnerTrieRef::Trie(ref trie) => match trie._match(suffix, ctx) {
-            None => {
-              //println!("TRACE: re:   repeat: no trie match");
-            }
-            Some((suffix_len, ridx)) => match (ctx, mat) {
-              (None, None) => {
-                //println!("TRACE: re:   repeat: trie N N");
-                mat = Some((rep_len + suffix_len, ridx));
-              }
-              (None, Some((old_len, old_idx))) => if old_len < rep_len + suffix_len
-                  || old_len == rep_len + suffix_len && ridx < old_idx {
-                //println!("TRACE: re:   repeat: trie N S");
-                mat = Some((rep_len + suffix_len, ridx));
-              }
-              (Some(ctx), _) => if ctx == ridx {
-                //println!("TRACE: re:   repeat: trie S _");
-                mat = Some((rep_len + suffix_len, ridx));
-                return mat;
-              }
-            }
-          }
+        for p in re_step_next(&paths[other], &opos, c).into_iter() {
+          next_others.push((other, p));
         }
       }
+      let next = (next_pos, next_others);
+      if !seen.contains_key(&next) {
+        seen.insert(next.clone(), Some(((pos.clone(), others.clone()), c)));
+        queue.push(next);
+      }
     }
-    mat = mat.or(zero_mat);
-    //println!("TRACE: re: ctx: {:?} depth: {} idx: {:?} char: '{}' suffix: '{}' match: {:?}", top_ctx, depth, self.index, c, suffix, mat);
-    mat
   }
+  shortest
+}
+
+/// Reconstruct the string that led to `state` in a breadth-first search.
+fn re_example<S: Clone + Ord>(seen: &BTreeMap<S, Option<(S, char)>>, state: S) -> String {
+  let mut cs = Vec::new();
+  let mut state = state;
+  while let Some(&Some((ref prev, c))) = seen.get(&state) {
+    cs.push(c);
+    state = prev.clone();
+  }
+  cs.iter().rev().collect()
 }
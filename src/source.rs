@@ -0,0 +1,233 @@
+use crate::parsing::{CharSpan};
+
+use std::borrow::{Cow};
+use std::str::{from_utf8};
+
+pub const UTF8_BOM: &'static [u8] = b"\xef\xbb\xbf";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SourceEncoding {
+  Utf8,
+  Latin1,
+}
+
+impl SourceEncoding {
+  /// Resolve an encoding name as it would appear in a PEP 263 coding
+  /// cookie, following the normalization of CPython's tokenizer
+  /// (`get_normal_name`).
+  pub fn from_name(name: &str) -> Option<SourceEncoding> {
+    let name = name.to_ascii_lowercase().replace('_', "-");
+    match &*name {
+      "utf-8" | "utf8" => Some(SourceEncoding::Utf8),
+      "latin-1" | "latin1" | "l1" |
+      "iso-8859-1" | "iso8859-1" | "iso-latin-1" => Some(SourceEncoding::Latin1),
+      _ => if name.starts_with("utf-8-") {
+        Some(SourceEncoding::Utf8)
+      } else if name.starts_with("latin-1-") ||
+                name.starts_with("iso-8859-1-") ||
+                name.starts_with("iso-latin-1-")
+      {
+        Some(SourceEncoding::Latin1)
+      } else {
+        None
+      }
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SourceErrorKind {
+  /// The bytes at the error span are not valid in the source encoding.
+  InvalidUtf8,
+  /// The coding cookie at the error span names an unsupported encoding.
+  UnknownEncoding,
+  /// A UTF-8 BOM was followed by a coding cookie for another encoding.
+  BomMismatch,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SourceError {
+  pub kind: SourceErrorKind,
+  pub span: CharSpan,
+}
+
+/// Source text decoded from bytes. Positions in the decoded text may be
+/// mapped back to byte offsets in the original input with `orig_offset`.
+pub struct SourceText<'a> {
+  text: Cow<'a, str>,
+  enc:  SourceEncoding,
+  bom:  bool,
+  // Positions in `text` of each decoded non-ASCII char (latin-1 only);
+  // each such char is one byte wider than its original byte.
+  wide: Vec<usize>,
+  err:  Option<SourceError>,
+}
+
+impl<'a> AsRef<str> for SourceText<'a> {
+  fn as_ref(&self) -> &str {
+    &*self.text
+  }
+}
+
+impl<'a> SourceText<'a> {
+  /// Decode source bytes, honoring a leading UTF-8 BOM and a PEP 263
+  /// coding cookie on the first or second line.
+  ///
+  /// Decoding does not fail outright: on error, the longest valid prefix
+  /// is kept as text, and the error is available from `error`.
+  pub fn decode(bytes: &'a [u8]) -> SourceText<'a> {
+    let (bom, base) = if bytes.starts_with(UTF8_BOM) {
+      (true, UTF8_BOM.len())
+    } else {
+      (false, 0)
+    };
+    let body = &bytes[base .. ];
+    let mut enc = SourceEncoding::Utf8;
+    let mut err = None;
+    if let Some((name_start, name_end)) = find_coding_cookie(body) {
+      let span = CharSpan{start: base + name_start, end: base + name_end};
+      // NB: the cookie name was checked to be ASCII.
+      let name = from_utf8(&body[name_start .. name_end]).unwrap();
+      match SourceEncoding::from_name(name) {
+        None => {
+          err = Some(SourceError{kind: SourceErrorKind::UnknownEncoding, span});
+        }
+        Some(SourceEncoding::Latin1) if bom => {
+          err = Some(SourceError{kind: SourceErrorKind::BomMismatch, span});
+        }
+        Some(e) => {
+          enc = e;
+        }
+      }
+    }
+    if let Some(err) = err {
+      // Keep the text up to the start of the offending cookie line.
+      let end = err.span.start - base;
+      let end = body[ .. end].iter().rposition(|&x| x == b'\n' || x == b'\r').map(|o| o + 1).unwrap_or(0);
+      let text = from_utf8(&body[ .. end]).map(Cow::Borrowed).unwrap_or(Cow::Borrowed(""));
+      return SourceText{text, enc, bom, wide: Vec::new(), err: Some(err)};
+    }
+    match enc {
+      SourceEncoding::Utf8 => {
+        match from_utf8(body) {
+          Ok(text) => {
+            SourceText{text: Cow::Borrowed(text), enc, bom, wide: Vec::new(), err: None}
+          }
+          Err(e) => {
+            let valid = e.valid_up_to();
+            let start = base + valid;
+            let end = match e.error_len() {
+              None => bytes.len(),
+              Some(n) => start + n
+            };
+            let text = from_utf8(&body[ .. valid]).unwrap();
+            let err = SourceError{kind: SourceErrorKind::InvalidUtf8, span: CharSpan{start, end}};
+            SourceText{text: Cow::Borrowed(text), enc, bom, wide: Vec::new(), err: Some(err)}
+          }
+        }
+      }
+      SourceEncoding::Latin1 => {
+        let mut text = String::with_capacity(body.len());
+        let mut wide = Vec::new();
+        for &x in body.iter() {
+          if x >= 0x80 {
+            wide.push(text.len());
+          }
+          text.push(x as char);
+        }
+        SourceText{text: Cow::Owned(text), enc, bom, wide, err: None}
+      }
+    }
+  }
+
+  pub fn as_str(&self) -> &str {
+    &*self.text
+  }
+
+  pub fn encoding(&self) -> SourceEncoding {
+    self.enc
+  }
+
+  pub fn has_bom(&self) -> bool {
+    self.bom
+  }
+
+  pub fn error(&self) -> Option<SourceError> {
+    self.err
+  }
+
+  /// Map a byte position in the decoded text to a byte offset in the
+  /// original input.
+  pub fn orig_offset(&self, pos: usize) -> usize {
+    let base = if self.bom { UTF8_BOM.len() } else { 0 };
+    let nwide = match self.wide.binary_search(&pos) {
+      Ok(i) => i,
+      Err(i) => i
+    };
+    base + pos - nwide
+  }
+
+  pub fn orig_span(&self, span: CharSpan) -> CharSpan {
+    if span.is_noloc() {
+      return span;
+    }
+    CharSpan{start: self.orig_offset(span.start), end: self.orig_offset(span.end)}
+  }
+}
+
+/// Find the encoding name of a PEP 263 coding cookie, i.e. a comment
+/// matching `^[ \t\f]*#.*?coding[:=][ \t]*([-\w.]+)` on the first line,
+/// or on the second line if the first is blank or a comment.
+///
+/// Returns the byte range of the encoding name.
+pub fn find_coding_cookie(bytes: &[u8]) -> Option<(usize, usize)> {
+  let mut line_start = 0;
+  for _ in 0 .. 2 {
+    let line_end = match bytes[line_start .. ].iter().position(|&x| x == b'\n' || x == b'\r') {
+      None => bytes.len(),
+      Some(o) => line_start + o
+    };
+    let line = &bytes[line_start .. line_end];
+    let indent = line.iter().position(|&x| !(x == b' ' || x == b'\t' || x == b'\x0c'));
+    match indent.map(|o| line[o]) {
+      None => {}
+      Some(b'#') => {
+        let mut o = 0;
+        while let Some(p) = find_bytes(&line[o .. ], b"coding") {
+          let mut q = o + p + 6;
+          if q < line.len() && (line[q] == b':' || line[q] == b'=') {
+            q += 1;
+            while q < line.len() && (line[q] == b' ' || line[q] == b'\t') {
+              q += 1;
+            }
+            let name_start = q;
+            while q < line.len() && (line[q].is_ascii_alphanumeric() ||
+                                     line[q] == b'_' || line[q] == b'-' || line[q] == b'.')
+            {
+              q += 1;
+            }
+            if name_start < q {
+              return Some((line_start + name_start, line_start + q));
+            }
+          }
+          o += p + 6;
+        }
+      }
+      // NB: only a blank or comment first line may precede the cookie.
+      Some(_) => return None
+    }
+    if line_end >= bytes.len() {
+      break;
+    }
+    line_start = if bytes[line_end .. ].starts_with(b"\r\n") {
+      line_end + 2
+    } else {
+      line_end + 1
+    };
+  }
+  None
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+  haystack.windows(needle.len()).position(|w| w == needle)
+}
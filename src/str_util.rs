@@ -1,6 +1,7 @@
 pub use crate::re::{len_utf8};
 
 pub use smol_str::{SmolStr};
+use unicode_normalization::{UnicodeNormalization};
 
 use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 
@@ -71,6 +72,16 @@ impl Display for SafeStr {
   }
 }
 
+/// Normalize an identifier to NFKC, per PEP 3131. ASCII identifiers are
+/// already normalized and are not re-scanned.
+pub fn nfkc_ident(s: &str) -> SafeStr {
+  if s.is_ascii() {
+    s.into()
+  } else {
+    s.nfkc().collect::<String>().into()
+  }
+}
+
 // NB: json-like string (un-)escape via rustc_serialize.
 
 fn decode_hex_escape_alt<I: Iterator<Item=char>>(src: &mut I) -> Result<u16, ()> {
@@ -198,6 +209,25 @@ fn decode_hex_escape<I: Iterator<Item=char>>(src: &mut I, off: &mut usize) -> Re
   Ok(n)
 }
 
+/// The length of the string literal at the start of `src`, as accepted by
+/// `unescape_str`, without building its value.
+pub fn scan_str(src: &str, delim: char) -> Result<usize, ()> {
+  if !src.starts_with(delim) || !delim.is_ascii() {
+    return unescape_str(src, delim).map(|(_, off)| off);
+  }
+  for (off, &b) in src.as_bytes().iter().enumerate().skip(1) {
+    if b == delim as u8 {
+      return Ok(off + 1);
+    } else if b == b'\\' {
+      // NB: escapes are rare, and left to `unescape_str`.
+      return unescape_str(src, delim).map(|(_, off)| off);
+    } else if b <= 0x1f {
+      return Err(());
+    }
+  }
+  Err(())
+}
+
 pub fn unescape_str(src: &str, delim: char) -> Result<(SmolStr, usize), ()> {
   let mut src = src.chars();
   let mut off = 0;
@@ -0,0 +1,67 @@
+
+static RANGES_0: &'static [(char, char)] = &[
+  ('0', '9'),
+];
+
+static RANGES_1: &'static [(char, char)] = &[
+];
+
+static RANGES_2: &'static [(char, char)] = &[
+  ('0', '9'), ('a', 'f'),
+];
+
+pub static HEX: ReStaticTrie = ReStaticTrie{
+  nrules:   2,
+  nodes:    &[
+    // 0
+    ReStaticNode{
+      index: None,
+      lit_term: &[],
+      literal: &[
+        ('0', 1),
+      ],
+      non_lit: &[
+        ReStaticEdge{kind: ReStaticKind::Unbound, zero: false, ranges: RANGES_0, next: ReStaticRef::Terminal(0)},
+      ],
+    },
+    // 1
+    ReStaticNode{
+      index: Some(1),
+      lit_term: &[],
+      literal: &[
+        ('x', 2),
+      ],
+      non_lit: &[],
+    },
+    // 2
+    ReStaticNode{
+      index: Some(1),
+      lit_term: &[],
+      literal: &[],
+      non_lit: &[
+        ReStaticEdge{kind: ReStaticKind::Mark(2), zero: true, ranges: RANGES_1, next: ReStaticRef::Trie(3)},
+      ],
+    },
+    // 3
+    ReStaticNode{
+      index: Some(1),
+      lit_term: &[],
+      literal: &[],
+      non_lit: &[
+        ReStaticEdge{kind: ReStaticKind::Unbound, zero: false, ranges: RANGES_2, next: ReStaticRef::Trie(4)},
+      ],
+    },
+    // 4
+    ReStaticNode{
+      index: Some(1),
+      lit_term: &[],
+      literal: &[],
+      non_lit: &[
+        ReStaticEdge{kind: ReStaticKind::Mark(3), zero: true, ranges: RANGES_1, next: ReStaticRef::Terminal(1)},
+      ],
+    },
+  ],
+  names:    &[
+    (1, 1, "digits"),
+  ],
+};
@@ -0,0 +1,71 @@
+use clothespin::lexer;
+use clothespin::lexer::{TokenCategory};
+use clothespin::re::{ReImageError, ReRegistry, ReTrie};
+use clothespin::str_util::{SafeStr};
+
+lexer! {
+  #[derive(Clone, PartialEq, Eq, Debug)]
+  pub enum CalcToken {
+    Space: Trivia = r"[ \t]+",
+    Let: Keyword = r"let",
+    Int(SafeStr): Literal = r"[0-9]+" => |s| CalcToken::Int(s.into()),
+    Ident(SafeStr): Name = r"[a-z][a-z0-9]*" => |s| CalcToken::Ident(s.into()),
+    Equal: Operator = r"=",
+    Plus: Operator = r"\+",
+    LParen: Delimiter = r"\(",
+    RParen: Delimiter = r"\)",
+  }
+}
+
+#[test]
+fn test_lexer_macro() {
+  let toks: Vec<_> = CalcToken::lex("let x = (1+y2)").map(|r| r.unwrap().1).collect();
+  assert_eq!(toks, vec![
+    CalcToken::Let,
+    CalcToken::Space,
+    CalcToken::Ident("x".into()),
+    CalcToken::Space,
+    CalcToken::Equal,
+    CalcToken::Space,
+    CalcToken::LParen,
+    CalcToken::Int("1".into()),
+    CalcToken::Plus,
+    CalcToken::Ident("y2".into()),
+    CalcToken::RParen,
+  ]);
+  assert_eq!(CalcToken::Int("1".into()).name(), "Int");
+  assert_eq!(CalcToken::Let.category(), TokenCategory::Keyword);
+  assert_eq!(CalcToken::RULES.len(), 8);
+  assert_eq!(CalcToken::RULES[2].pattern, r"[0-9]+");
+  let mut toks = CalcToken::lex("x $ y");
+  assert!(toks.next().unwrap().is_ok());
+  assert!(toks.next().unwrap().is_ok());
+  let err = toks.next().unwrap().unwrap_err();
+  assert_eq!((err.start, err.end), (2, 3));
+  assert!(toks.next().is_none());
+}
+
+#[test]
+fn test_trie_image() {
+  let ids: Vec<_> = CalcToken::RULES.iter().map(|r| r.name).collect();
+  let trie = CalcToken::fresh_trie();
+  let image = trie.to_image(&ids);
+  let trie2 = ReTrie::from_image(&image, &CalcToken::registry()).unwrap();
+  for text in ["let", "letx", "x1 ", "  +", "123(", "(", "$"] {
+    assert_eq!(trie.match_(text), trie2.match_(text), "{:?}", text);
+  }
+  assert_eq!(trie2.to_image(&ids), image);
+  let mut json = String::new();
+  trie.write_json(&ids, &mut json).unwrap();
+  assert!(json.starts_with(r#"{"version":2,"rules":["Space","Let","#));
+
+  let mut reg = ReRegistry::default();
+  reg.insert("Space", |_: &str| CalcToken::Space);
+  assert_eq!(ReTrie::from_image(&image, &reg).err(), Some(ReImageError::UnknownRule("Let".into())));
+  assert_eq!(ReTrie::<CalcToken>::from_image(b"CPRX", &reg).err(), Some(ReImageError::BadMagic));
+  let reg = CalcToken::registry();
+  assert_eq!(ReTrie::from_image(&image[ .. image.len() - 1], &reg).err(), Some(ReImageError::Truncated));
+  let mut bad = image.clone();
+  bad[4] = 1;
+  assert_eq!(ReTrie::from_image(&bad, &reg).err(), Some(ReImageError::BadVersion(1)));
+}
@@ -1,7 +1,14 @@
-use clothespin::parsing::{Token, Tokenizer};
+use clothespin::intern::{Intern, Interner, Symbol, SyncInterner};
+use clothespin::keyword::{SoftKeywords};
+use clothespin::lexer::{TokenCategory};
+use clothespin::parsing::{ByteTokenizer, NewlineForm, RefTokenizer, SymTokenizer, Token, TokenKind, TokenRef, TokenTable, Tokenizer, fresh_tokenizer_trie2, tokenize_parallel};
+use clothespin::profile::{LexerProfile, PROFILES, gen_static_lexer, tl_profile_trie};
+use clothespin::re::{ReDiagnostic};
 
 use std::fs::{File};
 use std::io::{Read};
+use std::sync::{Arc};
+use std::thread;
 
 #[test]
 fn test_tokenizer_data_1() {
@@ -68,3 +75,430 @@ fn test_tokenizer_data_2() {
     assert_eq!(tok, tok0);
   }
 }
+
+#[test]
+fn test_byte_tokenizer_bom() {
+  let s = b"\xef\xbb\xbfx = 1\n";
+  let toks: Vec<_> = ByteTokenizer::new(s).collect();
+  assert_eq!(toks[0].0.start, 3);
+  assert_eq!(toks[0].1, Token::Ident("x".into()));
+  assert_eq!(toks.last().unwrap().0.end, s.len());
+}
+
+#[test]
+fn test_byte_tokenizer_latin1() {
+  let s = b"# -*- coding: latin-1 -*-\nx = '\xe9'\ny\n";
+  let toks: Vec<_> = ByteTokenizer::new(s).collect();
+  let (lit_span, lit) = toks.iter().find(|&(_, tok)| match tok {
+    &Token::Lit(_) => true,
+    _ => false
+  }).unwrap();
+  assert_eq!(lit, &Token::Lit("\u{e9}".into()));
+  assert_eq!((lit_span.start, lit_span.end), (30, 33));
+  let (y_span, _) = toks.iter().find(|&(_, tok)| tok == &Token::Ident("y".into())).unwrap();
+  assert_eq!((y_span.start, y_span.end), (34, 35));
+}
+
+#[test]
+fn test_byte_tokenizer_invalid_utf8_in_lit() {
+  let s = b"x = 'caf\xe9'\n";
+  let toks: Vec<_> = ByteTokenizer::new(s).take(6).map(|(span, tok)| (span.start, span.end, tok)).collect();
+  assert_eq!(toks, vec![
+    (0, 1, Token::Ident("x".into())),
+    (1, 2, Token::Space),
+    (2, 3, Token::Equal),
+    (3, 4, Token::Space),
+    (8, 9, Token::_Err),
+    (9, 9, Token::_Eof),
+  ]);
+  let s = b"x = $\ny\xff";
+  let toks: Vec<_> = ByteTokenizer::new(s).take(6).map(|(span, tok)| (span.start, span.end, tok)).collect();
+  assert_eq!(toks, vec![
+    (0, 1, Token::Ident("x".into())),
+    (1, 2, Token::Space),
+    (2, 3, Token::Equal),
+    (3, 4, Token::Space),
+    (4, 4, Token::_Eof),
+    (4, 4, Token::_Eof),
+  ]);
+}
+
+#[test]
+fn test_byte_tokenizer_invalid_utf8() {
+  let s = b"x = 1\ny\xff\xfe\n";
+  let mut toks = ByteTokenizer::new(s);
+  let mut last = None;
+  for (span, tok) in &mut toks {
+    if tok.is_err() {
+      last = Some(span);
+      break;
+    }
+  }
+  let span = last.unwrap();
+  assert_eq!((span.start, span.end), (7, 8));
+  assert!(toks.next().unwrap().1.is_eof());
+}
+
+#[test]
+fn test_tokenizer_unicode_ident() {
+  let s = "café = π + 变量_1\nﬁle\n";
+  let toks: Vec<_> = Tokenizer::new(s).map(|(_, tok)| tok).collect();
+  let toks0 = vec![
+    Token::Ident("café".into()),
+    Token::Space,
+    Token::Equal,
+    Token::Space,
+    Token::Ident("π".into()),
+    Token::Space,
+    Token::Plus,
+    Token::Space,
+    Token::Ident("变量_1".into()),
+    Token::NL,
+    Token::Ident("file".into()),
+    Token::NL,
+  ];
+  assert_eq!(toks, toks0);
+}
+
+#[test]
+fn test_tokenizer_ascii_fallback() {
+  // NB: ASCII runs that continue into non-ASCII chars.
+  let s = "\t abcé1 = 12 # 注释 é\nz";
+  let toks: Vec<_> = Tokenizer::new(s).collect();
+  let toks0 = vec![
+    Token::IndentSpace(9),
+    Token::Ident("abcé1".into()),
+    Token::Space,
+    Token::Equal,
+    Token::Space,
+    Token::Int("12".into()),
+    Token::Space,
+    Token::CommentNL(" 注释 é".into()),
+    Token::NL,
+    Token::Ident("z".into()),
+  ];
+  assert_eq!(toks.iter().map(|(_, tok)| tok.clone()).collect::<Vec<_>>(), toks0);
+  assert_eq!((toks[1].0.start, toks[1].0.end), (2, 8));
+  assert_eq!((toks[7].0.start, toks[7].0.end), (14, 25));
+}
+
+#[test]
+fn test_ref_tokenizer() {
+  let s = "def f(x):\n  # é\n  return x + 12 * 'a\\tb' + ﬁle";
+  let toks: Vec<_> = RefTokenizer::new(s).collect();
+  assert_eq!(toks[0].1, TokenRef::Owned(Token::Def));
+  assert_eq!(toks[2].1, TokenRef::Ident("f"));
+  assert_eq!(toks[9].1, TokenRef::CommentNL(" é"));
+  assert_eq!(toks[18].1, TokenRef::Int("12"));
+  assert_eq!(toks[22].1, TokenRef::Lit("'a\\tb'"));
+  assert_eq!(toks[22].1.to_token(), Token::Lit("a\tb".into()));
+  assert_eq!(toks[26].1.to_token(), Token::Ident("file".into()));
+  let toks0: Vec<_> = Tokenizer::new(s).collect();
+  assert_eq!(toks.len(), toks0.len());
+  for ((span, tok), (span0, tok0)) in toks.iter().zip(toks0.iter()) {
+    assert_eq!((span.start, span.end), (span0.start, span0.end));
+    assert_eq!(&tok.to_token(), tok0);
+  }
+}
+
+#[test]
+fn test_tokenizer_interner() {
+  let intern = Interner::new();
+  let toks: Vec<_> = SymTokenizer::new(&intern, "self.x = 'self' + ﬁ + 'a\\tb'").collect();
+  let syms: Vec<_> = toks.iter().map(|&(_, _, sym)| sym).collect();
+  assert_eq!(syms, vec![
+    Some(Symbol(0)), None, Some(Symbol(1)), None, None, None,
+    Some(Symbol(0)), None, None, None, Some(Symbol(2)), None,
+    None, None, Some(Symbol(3)),
+  ]);
+  assert_eq!(toks[6].1, TokenRef::Lit("'self'"));
+  assert_eq!(intern.resolve(Symbol(2)), Some("fi".into()));
+  assert_eq!(intern.resolve(Symbol(3)), Some("a\tb".into()));
+  assert_eq!(intern.get("x"), Some(Symbol(1)));
+  let toks: Vec<_> = RefTokenizer::new("x + y").with_interner(&intern).collect();
+  assert_eq!(toks[0].2, Some(Symbol(1)));
+  assert_eq!(toks[1].2, None);
+  assert_eq!(intern.len(), 5);
+
+  let intern = Arc::new(SyncInterner::new());
+  let threads: Vec<_> = ["a b c", "c b a"].iter().map(|&s| {
+    let intern = intern.clone();
+    thread::spawn(move || {
+      SymTokenizer::new(intern, s)
+        .filter_map(|(_, tok, sym)| sym.map(|sym| (tok.to_token(), sym)))
+        .collect::<Vec<_>>()
+    })
+  }).collect();
+  for t in threads {
+    for (tok, sym) in t.join().unwrap() {
+      assert_eq!(Some(tok), intern.resolve(sym).map(Token::Ident));
+    }
+  }
+  assert_eq!(intern.len(), 3);
+}
+
+#[test]
+fn test_token_table() {
+  assert_eq!(std::mem::size_of::<TokenKind>(), 1);
+  assert_eq!(Token::Ident("x".into()).kind(), TokenKind::Ident);
+  assert_eq!(Token::Def.category(), TokenCategory::Keyword);
+  assert_eq!(Token::StarStarEq.category(), TokenCategory::Operator);
+  assert_eq!(Token::LBrack.category(), TokenCategory::Delimiter);
+  assert_eq!(Token::Int("1".into()).category(), TokenCategory::Literal);
+  assert_eq!(Token::IndentSpace(4).category(), TokenCategory::Trivia);
+  assert_eq!(TokenKind::Gt.bare(), Some(Token::Gt));
+  assert!(TokenKind::Lit.has_payload());
+  for path in ["test_data/parser-1.txt", "test_data/parser-2.txt"] {
+    let mut f = File::open(path).unwrap();
+    let mut s = String::new();
+    f.read_to_string(&mut s).unwrap();
+    let table = TokenTable::new(&s);
+    let toks0: Vec<_> = Tokenizer::new(&s).collect();
+    assert_eq!(table.len(), toks0.len());
+    for ((span, tok), (span0, tok0)) in table.iter().zip(toks0.into_iter()) {
+      assert_eq!((span.start, span.end), (span0.start, span0.end));
+      assert_eq!(tok, tok0);
+    }
+  }
+  let table = TokenTable::new("x = x + 'x\n");
+  assert_eq!(table.kinds, vec![
+    TokenKind::Ident, TokenKind::Space, TokenKind::Equal, TokenKind::Space,
+    TokenKind::Ident, TokenKind::Space, TokenKind::Plus, TokenKind::Space,
+    TokenKind::_Eof,
+  ]);
+  assert_eq!(table.symbol(0), table.symbol(4));
+  assert_eq!(table.interner().len(), 1);
+}
+
+#[test]
+fn test_tokenize_parallel() {
+  let mut s = String::new();
+  for i in 0 .. 200 {
+    s.push_str(&format!("def f{}(x,\n      y):  # é {}\r\n\tif x: return [x,\n  'y{}']\n\n", i, i, i));
+  }
+  let profile = LexerProfile::default();
+  let mut toks0 = Vec::new();
+  for (span, tok) in Tokenizer::new2(tl_profile_trie(profile), s.as_str()) {
+    let eof = tok.is_eof();
+    toks0.push((span.start, span.end, tok));
+    if eof {
+      break;
+    }
+  }
+  for &n in [1, 2, 4, 7].iter() {
+    let toks: Vec<_> = tokenize_parallel(profile, &s, n).into_iter()
+      .map(|(span, tok)| (span.start, span.end, tok))
+      .collect();
+    assert_eq!(toks, toks0, "{}", n);
+  }
+  // NB: a lex error stops the tokens, as in `Tokenizer`.
+  s.push_str("x = 'oops\n");
+  s.push_str(&s.clone());
+  let toks = tokenize_parallel(profile, &s, 4);
+  assert!(toks.last().unwrap().1.is_eof());
+  assert_eq!(toks.len(), toks0.len() + 5);
+}
+
+#[test]
+fn test_tokenizer_newline_forms() {
+  let s = "if x:\r\n  y # c\r  z\n";
+  let toks: Vec<_> = Tokenizer::new(s).collect();
+  let toks0 = vec![
+    Token::If,
+    Token::Space,
+    Token::Ident("x".into()),
+    Token::Colon,
+    Token::NL,
+    Token::IndentSpace(2),
+    Token::Ident("y".into()),
+    Token::Space,
+    Token::CommentNL(" c".into()),
+    Token::NL,
+    Token::IndentSpace(2),
+    Token::Ident("z".into()),
+    Token::NL,
+  ];
+  assert_eq!(toks.iter().map(|(_, tok)| tok.clone()).collect::<Vec<_>>(), toks0);
+  let forms: Vec<_> = toks.iter().filter(|&(_, tok)| tok == &Token::NL)
+    .map(|&(span, _)| NewlineForm::of(&s[span.start .. span.end]).unwrap())
+    .collect();
+  assert_eq!(forms, vec![NewlineForm::CrLf, NewlineForm::Cr, NewlineForm::Lf]);
+}
+
+#[test]
+fn test_tokenizer_soft_keywords() {
+  let s = "match = re.match(x)\nmatch p:\n  case [a, _]:\n    type(_)\n  case _: pass\ntype X = int\n";
+  let toks: Vec<_> = SoftKeywords::new(Tokenizer::new(s))
+    .map(|(_, tok)| tok)
+    .filter(|tok| !(tok.is_space() || tok == &Token::NL))
+    .collect();
+  let toks0 = vec![
+    Token::Ident("match".into()),
+    Token::Equal,
+    Token::Ident("re".into()),
+    Token::Dot,
+    Token::Ident("match".into()),
+    Token::LParen,
+    Token::Ident("x".into()),
+    Token::RParen,
+    Token::Match,
+    Token::Ident("p".into()),
+    Token::Colon,
+    Token::IndentSpace(2),
+    Token::Case,
+    Token::LBrack,
+    Token::Ident("a".into()),
+    Token::Comma,
+    Token::Place,
+    Token::RBrack,
+    Token::Colon,
+    Token::IndentSpace(4),
+    Token::Ident("type".into()),
+    Token::LParen,
+    Token::Ident("_".into()),
+    Token::RParen,
+    Token::IndentSpace(2),
+    Token::Case,
+    Token::Place,
+    Token::Colon,
+    Token::Pass,
+    Token::Type,
+    Token::Ident("X".into()),
+    Token::Equal,
+    Token::Ident("int".into()),
+  ];
+  assert_eq!(toks, toks0);
+}
+
+#[test]
+fn test_tokenizer_profiles() {
+  let s = "a[:-1] if (n := x<-1) else where @= 1_000\n";
+  let toks: Vec<_> = Tokenizer::new2(tl_profile_trie(LexerProfile::Py312), s)
+    .map(|(_, tok)| tok)
+    .filter(|tok| !tok.is_space())
+    .collect();
+  let toks0 = vec![
+    Token::Ident("a".into()),
+    Token::LBrack,
+    Token::Colon,
+    Token::Dash,
+    Token::Int("1".into()),
+    Token::RBrack,
+    Token::If,
+    Token::LParen,
+    Token::Ident("n".into()),
+    Token::ColonEq,
+    Token::Ident("x".into()),
+    Token::Lt,
+    Token::Dash,
+    Token::Int("1".into()),
+    Token::RParen,
+    Token::Else,
+    Token::Ident("where".into()),
+    Token::AtEq,
+    Token::Int("1_000".into()),
+    Token::NL,
+  ];
+  assert_eq!(toks, toks0);
+  let toks: Vec<_> = Tokenizer::new2(tl_profile_trie(LexerProfile::Clothespin), s)
+    .map(|(_, tok)| tok)
+    .filter(|tok| !tok.is_space())
+    .collect();
+  assert_eq!(&toks[2], &Token::LDash);
+  assert_eq!(&toks[10], &Token::LArrow);
+  assert_eq!(&toks[14], &Token::Where);
+  assert_eq!(&toks[16], &Token::Int("1_000".into()));
+  assert!(LexerProfile::Py38.keyword_class("match").is_none());
+  assert!(LexerProfile::Py310.keyword_class("match").is_some());
+  assert!(LexerProfile::Py310.keyword_class("type").is_none());
+}
+
+#[test]
+fn test_tokenizer_operators() {
+  let ops = vec![
+    (":=",  Token::ColonEq),
+    ("@=",  Token::AtEq),
+    ("&=",  Token::AmpEq),
+    ("|=",  Token::BarEq),
+    ("^=",  Token::CaretEq),
+    ("**=", Token::StarStarEq),
+    ("//=", Token::SlashSlashEq),
+    ("<>",  Token::Neq),
+    ("...", Token::DotDotDot),
+  ];
+  for profile in [LexerProfile::Py38, LexerProfile::Py312, LexerProfile::Clothespin] {
+    for &(op, ref tok0) in ops.iter() {
+      let s = format!("x {} y", op);
+      let toks: Vec<_> = Tokenizer::new2(tl_profile_trie(profile), &s).map(|(_, tok)| tok).collect();
+      assert_eq!(toks, vec![
+        Token::Ident("x".into()),
+        Token::Space,
+        tok0.clone(),
+        Token::Space,
+        Token::Ident("y".into()),
+      ], "{:?} {:?}", profile, op);
+    }
+  }
+  let toks: Vec<_> = Tokenizer::new2(tl_profile_trie(LexerProfile::Clothespin), "a;;b..c")
+    .map(|(_, tok)| tok).collect();
+  assert_eq!(toks, vec![
+    Token::Ident("a".into()),
+    Token::SemiSemi,
+    Token::Ident("b".into()),
+    Token::Dot,
+    Token::Dot,
+    Token::Ident("c".into()),
+  ]);
+  let toks: Vec<_> = Tokenizer::new2(tl_profile_trie(LexerProfile::Py312), "a;;b")
+    .map(|(_, tok)| tok).collect();
+  assert_eq!(&toks[1 .. 3], &[Token::Semi, Token::Semi]);
+}
+
+#[test]
+fn test_static_lexer_in_sync() {
+  // NB: if this fails, run `make gen`.
+  assert!(gen_static_lexer() == include_str!("../src/static_lexer.rs"));
+}
+
+#[test]
+fn test_static_lexer_matches_fresh() {
+  let mut f = File::open("test_data/parser-1.txt").unwrap();
+  let mut s = String::new();
+  f.read_to_string(&mut s).unwrap();
+  s.push_str("\nx := a[:-1] <- b ~> c ;; 1_000 \u{e9}t\u{e9} <> ...\r\n");
+  for &(profile, _) in PROFILES.iter() {
+    let fresh = fresh_tokenizer_trie2(profile, &profile.keyword_table());
+    let toks: Vec<_> = Tokenizer::new2(tl_profile_trie(profile), &s).collect();
+    let toks2: Vec<_> = Tokenizer::new2(fresh, &s).collect();
+    assert_eq!(toks.len(), toks2.len(), "{:?}", profile);
+    for (&(span, ref tok), &(span2, ref tok2)) in toks.iter().zip(toks2.iter()) {
+      assert_eq!((span.start, span.end, tok), (span2.start, span2.end, tok2), "{:?}", profile);
+    }
+  }
+}
+
+#[test]
+fn test_tokenizer_rules_diagnose() {
+  for &(profile, _) in PROFILES.iter() {
+    let kws = profile.keyword_table();
+    for diag in tl_profile_trie(profile).diagnose().into_iter() {
+      match diag {
+        // NB: only the keywords are expected to overlap (with identifiers).
+        ReDiagnostic::Overlap{ref example, ..} if kws.get(example).is_some() => {}
+        _ => panic!("{:?} {:?}", profile, diag)
+      }
+    }
+  }
+}
+
+#[test]
+fn test_tokenizer_match_all() {
+  let mut f = File::open("test_data/parser-1.txt").unwrap();
+  let mut s = String::new();
+  f.read_to_string(&mut s).unwrap();
+  let trie = tl_profile_trie(LexerProfile::Clothespin);
+  for (pos, _) in s.char_indices() {
+    let mats = trie.match_all_at(&s, pos);
+    assert_eq!(trie.match_at(&s, pos), mats.into_iter().next().map(|(_, tok, end)| (tok, end)), "{}", pos);
+  }
+}
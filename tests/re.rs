@@ -0,0 +1,399 @@
+use clothespin::re::{ReDiagnostic, ReImageError, ReRegistry, ReRuleTrie, ReStaticWriter, ReTrace, ReTrie, ReTrieError, RuleId};
+
+#[test]
+fn test_trie_assertions() {
+  let mut tr = ReTrie::default();
+  tr.push(r"if\b", |_| "if");
+  tr.push(r"(?m)^#", |_| "hash");
+  tr.push(r"-(?![:>])", |_| "minus");
+  tr.push(r"\+(?=[0-9])", |_| "sign");
+  tr.push(r"x\B[a-z]*", |_| "x");
+  tr.push(r"end$", |_| "end");
+  assert_eq!(tr.match_("if x"), Some(("if", 2)));
+  assert_eq!(tr.match_("if"), Some(("if", 2)));
+  assert_eq!(tr.match_("if("), Some(("if", 2)));
+  assert_eq!(tr.match_("iffy"), None);
+  assert_eq!(tr.match_at("a if", 2), Some(("if", 4)));
+  assert_eq!(tr.match_("#"), Some(("hash", 1)));
+  assert_eq!(tr.match_at("a\n#", 2), Some(("hash", 3)));
+  assert_eq!(tr.match_at("a\r\n#", 3), Some(("hash", 4)));
+  assert_eq!(tr.match_at("a #", 2), None);
+  assert_eq!(tr.match_("-1"), Some(("minus", 1)));
+  assert_eq!(tr.match_("-"), Some(("minus", 1)));
+  assert_eq!(tr.match_("-:"), None);
+  assert_eq!(tr.match_("->"), None);
+  assert_eq!(tr.match_("+1"), Some(("sign", 1)));
+  assert_eq!(tr.match_("+a"), None);
+  assert_eq!(tr.match_("+"), None);
+  assert_eq!(tr.match_("xyz"), Some(("x", 3)));
+  assert_eq!(tr.match_("x "), None);
+  assert_eq!(tr.match_("end"), Some(("end", 3)));
+  assert_eq!(tr.match_("end\n"), None);
+
+  let ids = ["if", "hash", "minus", "sign", "x", "end"];
+  let mut reg = ReRegistry::default();
+  for &id in ids.iter() {
+    reg.insert(id, move |_| id);
+  }
+  let tr2 = ReTrie::from_image(&tr.to_image(&ids), &reg).unwrap();
+  for text in ["if x", "iffy", "-:", "-1", "+1", "+a", "xyz", "x ", "end", "end\n"] {
+    assert_eq!(tr.match_(text), tr2.match_(text), "{:?}", text);
+  }
+}
+
+#[test]
+fn test_trie_flags() {
+  let mut tr = ReTrie::default();
+  tr.push(r"(?i)select\b", |_| "select");
+  tr.push(r"(?i:from)\b", |_| "from");
+  tr.push(r"(?i)x[a-f]+", |_| "hex");
+  tr.push(r"(?-u)\w+", |_| "word");
+  tr.push(r"(?-u:[+\-])[0-9]", |_| "signed");
+  assert_eq!(tr.match_("SELECT *"), Some(("select", 6)));
+  assert_eq!(tr.match_("SeLeCt"), Some(("select", 6)));
+  assert_eq!(tr.match_("From"), Some(("from", 4)));
+  assert_eq!(tr.match_("XaBc"), Some(("hex", 4)));
+  assert_eq!(tr.match_("selected"), Some(("word", 8)));
+  assert_eq!(tr.match_("ab\u{e9}"), Some(("word", 2)));
+  assert_eq!(tr.match_("-1"), Some(("signed", 2)));
+  assert_eq!(tr.match_("\u{e9}"), None);
+  let mut tr = ReTrie::default();
+  tr.push(r"(?i)select(?![a-z])", |_| "select");
+  tr.push(r"(?i:from)(?![a-z])", |_| "from");
+  tr.push(r"[a-z]+", |_| "word");
+  assert_eq!(tr.match_("SELECT *"), Some(("select", 6)));
+  assert_eq!(tr.match_("SELECTED"), None);
+  assert_eq!(tr.match_("selected"), Some(("word", 8)));
+  assert_eq!(tr.match_("FROMA"), Some(("from", 4)));
+  assert_eq!(tr.match_("FROMa"), None);
+}
+
+#[test]
+fn test_trie_lazy() {
+  let mut tr = ReTrie::default();
+  tr.push(r"(?s)/\*.*?\*/", |_| "comment");
+  tr.push(r"<<<[A-Z]+?;", |_| "heredoc");
+  tr.push(r"/", |_| "slash");
+  tr.push(r"ab??", |_| "a");
+  tr.push(r#"".*""#, |_| "never");
+  assert_eq!(tr.match_("/* a */ b */"), Some(("comment", 7)));
+  assert_eq!(tr.match_("/*\n*/"), Some(("comment", 5)));
+  assert_eq!(tr.match_("/**/"), Some(("comment", 4)));
+  assert_eq!(tr.match_("/* a"), Some(("slash", 1)));
+  assert_eq!(tr.match_("<<<EOF;x;"), Some(("heredoc", 7)));
+  assert_eq!(tr.match_("<<<;"), None);
+  assert_eq!(tr.match_("ab"), Some(("a", 1)));
+  assert_eq!(tr.match_(r#""x""#), None);
+
+  let ids = ["comment", "heredoc", "slash", "a", "never"];
+  let mut reg = ReRegistry::default();
+  for &id in ids.iter() {
+    reg.insert(id, move |_| id);
+  }
+  let tr2 = ReTrie::from_image(&tr.to_image(&ids), &reg).unwrap();
+  for text in ["/* a */ b */", "/* a", "<<<EOF;x;", "ab"] {
+    assert_eq!(tr.match_(text), tr2.match_(text), "{:?}", text);
+  }
+}
+
+#[test]
+fn test_trie_try_push() {
+  let mut tr = ReTrie::default();
+  tr.push(r"a", |_| 0);
+  match tr.try_push(r"a(b", |_| 1) {
+    Err(ReTrieError::Syntax{span, ..}) => assert_eq!((span.start, span.end), (1, 2)),
+    e => panic!("{:?}", e)
+  }
+  match tr.try_push(r"x([a-z])*", |_| 1) {
+    Err(ReTrieError::Unsupported{kind, span}) => {
+      assert_eq!(kind, "Repetition");
+      assert_eq!((span.start, span.end), (1, 9));
+    }
+    e => panic!("{:?}", e)
+  }
+  match tr.try_push(r"x(?:ab|cd)", |_| 1) {
+    Err(ReTrieError::Unsupported{kind, span}) => {
+      assert_eq!(kind, "Alternation");
+      assert_eq!((span.start, span.end), (4, 9));
+    }
+    e => panic!("{:?}", e)
+  }
+  match tr.try_push(r"xa{2,3}", |_| 1) {
+    Err(ReTrieError::Unsupported{kind, span}) => {
+      assert_eq!(kind, "Repetition");
+      assert_eq!((span.start, span.end), (1, 7));
+    }
+    e => panic!("{:?}", e)
+  }
+  match tr.try_push(r"-(?=ab)", |_| 1) {
+    Err(ReTrieError::Unsupported{kind, span}) => {
+      assert_eq!(kind, "Lookahead");
+      assert_eq!((span.start, span.end), (4, 6));
+    }
+    e => panic!("{:?}", e)
+  }
+  match tr.try_push(r"a(?=b)(c)", |_| 1) {
+    Err(ReTrieError::Unsupported{kind, span}) => {
+      assert_eq!(kind, "Lookahead");
+      assert_eq!((span.start, span.end), (1, 6));
+    }
+    e => panic!("{:?}", e)
+  }
+  let mut tr2 = ReTrie::default();
+  tr2.try_push(r"[(?=]+(x)", |_| 0).unwrap();
+  assert_eq!(tr2.match_("(=?x"), Some((0, 4)));
+  match tr.try_push(r"[0-9]*\b", |_| 1) {
+    Err(ReTrieError::EmptyMatch) => {}
+    e => panic!("{:?}", e)
+  }
+  tr.try_push(r"b+", |_| 1).unwrap();
+  assert_eq!(tr.match_("a"), Some((0, 1)));
+  assert_eq!(tr.match_("bb"), Some((1, 2)));
+}
+
+#[test]
+fn test_trie_diagnose() {
+  let mut tr = ReTrie::default();
+  tr.push(r"_", |_| 0);
+  tr.push(r"[a-z]+", |_| 1);
+  tr.push(r"if", |_| 2);
+  tr.push(r"[_a-z][_a-z0-9]*", |_| 3);
+  tr.push(r"[0-9]+", |_| 4);
+  tr.push(r"(?s).", |_| 5);
+  let diags = tr.diagnose();
+  assert!(diags.contains(&ReDiagnostic::Overlap{rule: 3, other: 0, example: "_".into()}));
+  assert!(diags.contains(&ReDiagnostic::Overlap{rule: 3, other: 1, example: "a".into()}));
+  assert!(diags.contains(&ReDiagnostic::Overlap{rule: 2, other: 1, example: "if".into()}));
+  assert!(diags.contains(&ReDiagnostic::Unreachable{rule: 2, other: 1, example: "if".into()}));
+  assert!(diags.contains(&ReDiagnostic::Overlap{rule: 5, other: 4, example: "0".into()}));
+  assert!(!diags.iter().any(|d| match d {
+    &ReDiagnostic::Overlap{rule: 4, other: 1, ..} => true,
+    &ReDiagnostic::Unreachable{rule, ..} => rule != 2,
+    &ReDiagnostic::EmptyMatch{..} => true,
+    _ => false
+  }), "{:?}", diags);
+}
+
+#[test]
+fn test_trie_match_all() {
+  let mut tr = ReTrie::default();
+  tr.push(r"<", |s| s.to_string());
+  tr.push(r"<-", |s| s.to_string());
+  tr.push(r"<--", |s| s.to_string());
+  tr.push(r"<[a-z]+", |s| s.to_string());
+  tr.push(r"<[-a-z]+", |s| s.to_string());
+  tr.push(r"if\b", |s| s.to_string());
+  tr.push(r"[a-z]+", |s| s.to_string());
+  assert_eq!(tr.match_all("<--x"), vec![
+    (4, "<--x".to_string(), 4),
+    (2, "<--".to_string(), 3),
+    (1, "<-".to_string(), 2),
+    (0, "<".to_string(), 1),
+  ]);
+  assert_eq!(tr.match_all("<ab"), vec![
+    (3, "<ab".to_string(), 3),
+    (4, "<ab".to_string(), 3),
+    (0, "<".to_string(), 1),
+  ]);
+  assert_eq!(tr.match_all("if"), vec![
+    (5, "if".to_string(), 2),
+    (6, "if".to_string(), 2),
+  ]);
+  assert_eq!(tr.match_all_at("iffy", 0), vec![(6, "iffy".to_string(), 4)]);
+  assert_eq!(tr.match_all_at("x if", 2), vec![
+    (5, "if".to_string(), 4),
+    (6, "if".to_string(), 4),
+  ]);
+  assert_eq!(tr.match_all("?"), vec![]);
+  for text in ["<--x", "<ab", "if", "iffy", "<"] {
+    assert_eq!(tr.match_(text), tr.match_all(text).into_iter().next().map(|(_, v, n)| (v, n)));
+  }
+}
+
+#[test]
+fn test_trie_captures() {
+  let mut tr = ReTrie::default();
+  tr.push_match(r"0x([0-9a-f]+)", |m| (16, m.get(1).unwrap().to_string()));
+  tr.push_match(r"0b(?P<digits>[01]+)(?P<suffix>[a-z]*)", |m| (2, format!("{}/{}", m.name("digits").unwrap(), m.name("suffix").unwrap())));
+  tr.push_match(r#""(?P<body>[^"]*)""#, |m| {
+    let span = m.name_span("body").unwrap();
+    (0, format!("{}..{}", span.start, span.end))
+  });
+  tr.push_match(r"<((a*)(a*?))(a*)>", |m| {
+    assert_eq!(m.ngroups(), 5);
+    (m.get(2).unwrap().len(), format!("{}{}", m.get(3).unwrap(), m.get(4).unwrap()))
+  });
+  tr.push(r"[0-9]+", |s| (10, s.to_string()));
+  assert_eq!(tr.match_("0xff+1"), Some(((16, "ff".to_string()), 4)));
+  assert_eq!(tr.match_("0b101u8"), Some(((2, "101/u".to_string()), 6)));
+  assert_eq!(tr.match_(r#""ab" x"#), Some(((0, "1..3".to_string()), 4)));
+  assert_eq!(tr.match_("<aaa>"), Some(((3, "".to_string()), 5)));
+  assert_eq!(tr.match_("0"), Some(((10, "0".to_string()), 1)));
+  assert_eq!(tr.match_all("0x1")[0], (0, (16, "1".to_string()), 3));
+  assert_eq!(tr.apply(RuleId(0), "0x1f"), Some((16, "1f".to_string())));
+  assert_eq!(tr.apply(RuleId(0), "0b1"), None);
+  assert_eq!(tr.apply(RuleId(4), "0b1"), Some((10, "0b1".to_string())));
+  assert_eq!(tr.apply(RuleId(5), "1"), None);
+
+  let ids = ["hex", "bin", "str", "angle", "dec"];
+  let image = tr.to_image(&ids);
+  let mut reg = ReRegistry::default();
+  reg.insert_match("hex", |m| (16, m.get(1).unwrap().to_string()));
+  reg.insert_match("bin", |m| (2, m.name("suffix").unwrap().to_string()));
+  reg.insert_match("str", |m| (0, m.name("body").unwrap().to_string()));
+  reg.insert_match("angle", |m| (m.get(2).unwrap().len(), String::new()));
+  reg.insert("dec", |s| (10, s.to_string()));
+  let tr2 = ReTrie::from_image(&image, &reg).unwrap();
+  assert_eq!(tr2.match_("0b1z"), Some(((2, "z".to_string()), 4)));
+  assert_eq!(tr2.match_(r#""a b""#), Some(((0, "a b".to_string()), 5)));
+  assert_eq!(tr2.to_image(&ids), image);
+}
+
+#[test]
+fn test_trie_find() {
+  let mut tr = ReTrie::default();
+  tr.push(r"\bdef\b", |_| "def".to_string());
+  tr.push(r"0x[0-9a-f]+", |s| s.to_string());
+  tr.push(r"[0-9]+", |s| s.to_string());
+  tr.push(r"é+", |s| s.to_string());
+  let text = "undef x = 0xff; def f(): return 10 # éé";
+  let found: Vec<_> = tr.find_iter(text).map(|(ridx, v, span)| (ridx, v, span.start, span.end)).collect();
+  assert_eq!(found, vec![
+    (1, "0xff".to_string(), 10, 14),
+    (0, "def".to_string(), 16, 19),
+    (2, "10".to_string(), 32, 34),
+    (3, "éé".to_string(), 37, 41),
+  ]);
+  let (ridx, _, span) = tr.find_at(text, 17).unwrap();
+  assert_eq!((ridx, span.start, span.end), (2, 32, 34));
+  assert!(tr.find_at("undef", 2).is_none());
+  assert!(tr.find("no matches here").is_none());
+  assert!(tr.find("").is_none());
+}
+
+#[test]
+fn test_rule_trie() {
+  let mut tr = ReRuleTrie::new();
+  let kw_if = tr.push(r"if\b");
+  let ident = tr.push(r"[a-z_][a-z0-9_]*");
+  let int = tr.push(r"[0-9]+");
+  let hex = tr.push(r"0x(?P<digits>[0-9a-f]+)");
+  assert_eq!((kw_if, ident, int, hex), (RuleId(0), RuleId(1), RuleId(2), RuleId(3)));
+  assert_eq!(tr.len(), 4);
+  assert!(tr.try_push(r"ab|cd").is_err());
+  assert_eq!(tr.len(), 4);
+  let mut toks = Vec::new();
+  let text = "if iffy 0x1f 42";
+  for (rule, span) in tr.find_iter(text) {
+    let tok = &text[span.start .. span.end];
+    toks.push(match rule {
+      r if r == kw_if => "kw".to_string(),
+      r if r == ident => format!("id:{}", tok),
+      r if r == hex => format!("hex:{}", tr.captures(rule, tok).unwrap().name("digits").unwrap()),
+      _ => format!("int:{}", tok),
+    });
+  }
+  assert_eq!(toks, vec!["kw", "id:iffy", "hex:1f", "int:42"]);
+  assert!(tr.captures(hex, "0x").is_none());
+  assert!(tr.captures(hex, "42").is_none());
+  assert!(tr.captures(RuleId(4), "42").is_none());
+  assert_eq!(tr.match_("0x1f+"), Some((hex, 4)));
+  assert_eq!(tr.match_at("x 42", 2), Some((int, 4)));
+  assert_eq!(tr.match_all("if"), vec![(kw_if, 2), (ident, 2)]);
+
+  let tr2 = tr.clone();
+  assert_eq!(tr2, tr);
+  let image = tr.to_image(&["if", "ident", "int", "hex"]);
+  let (tr3, ids) = ReRuleTrie::from_image(&image).unwrap();
+  assert_eq!(tr3, tr);
+  assert_eq!(ids, vec!["if", "ident", "int", "hex"]);
+  let mut tr4 = tr.clone();
+  tr4.push(r"\+");
+  assert!(tr4 != tr);
+  assert!(format!("{:?}", tr).starts_with("ReRuleTrie"));
+}
+
+mod static_hex {
+  use clothespin::re::{ReStaticEdge, ReStaticKind, ReStaticNode, ReStaticRef, ReStaticTrie};
+
+  include!("../test_data/re_static_hex.rs");
+}
+
+#[test]
+fn test_rule_trie_static() {
+  let mut tr = ReRuleTrie::new();
+  let int = tr.push(r"[0-9]+");
+  let hex = tr.push(r"0x(?P<digits>[0-9a-f]+)");
+  let mut sw = ReStaticWriter::default();
+  sw.write_trie("HEX", &tr).unwrap();
+  let mut src = String::new();
+  sw.finish(&mut src).unwrap();
+  assert_eq!(src.split_once('\n').unwrap().1, include_str!("../test_data/re_static_hex.rs"));
+  let tr2 = ReRuleTrie::from_static(&static_hex::HEX);
+  assert_eq!(tr2, tr);
+  assert_eq!(tr2.match_("42"), Some((int, 2)));
+  let m = tr2.captures(hex, "0x1f").unwrap();
+  assert_eq!(m.name("digits"), Some("1f"));
+  assert_eq!(m.get(1), Some("1f"));
+}
+
+#[test]
+fn test_rule_trie_image_malformed() {
+  let mut tr = ReRuleTrie::new();
+  tr.push(r"[b-d][f-h]");
+  let image = tr.to_image(&["bd"]);
+  assert!(ReRuleTrie::from_image(&image).is_ok());
+  let le = |x: u32| x.to_le_bytes();
+  let ranges: Vec<u8> = [1, 'b' as u32, 'd' as u32].iter().flat_map(|&x| le(x)).collect();
+  let at = image.windows(ranges.len()).position(|w| w == &ranges[ .. ]).unwrap();
+  // An inverted range.
+  let mut bad = image.clone();
+  bad[at + 4 .. at + 8].copy_from_slice(&le('e' as u32));
+  assert_eq!(ReRuleTrie::from_image(&bad).err(), Some(ReImageError::Malformed(at + 4)));
+  // Overlapping ranges.
+  let mut bad = image[ .. at].to_vec();
+  for &x in [2, 'b' as u32, 'd' as u32, 'c' as u32, 'e' as u32].iter() {
+    bad.extend_from_slice(&le(x));
+  }
+  bad.extend_from_slice(&image[at + 12 .. ]);
+  assert_eq!(ReRuleTrie::from_image(&bad).err(), Some(ReImageError::Malformed(at + 12)));
+  // A class edge with no ranges.
+  let mut bad = image[ .. at].to_vec();
+  bad.extend_from_slice(&le(0));
+  bad.extend_from_slice(&image[at + 12 .. ]);
+  assert!(ReRuleTrie::from_image(&bad).is_err());
+}
+
+#[test]
+fn test_trie_dump() {
+  let mut tr = ReRuleTrie::new();
+  tr.push(r"ab");
+  tr.push(r"a[0-9]+");
+  tr.push(r"x\b");
+  assert_eq!(tr.dump(), "\
+node 0
+  'a' -> 1
+  'x' -> 2
+node 1
+  'b' => rule 0
+  #0 [0-9]+ => rule 1
+node 2 (rule 2)
+  #0 WordBoundary => rule 2
+");
+  let dot = tr.to_dot();
+  assert!(dot.starts_with("digraph retrie {\n"));
+  assert!(dot.contains("  n0 -> n1 [label=\"'a'\"];\n"));
+  assert!(dot.contains("  n1 -> r1 [label=\"[0-9]+\"];\n"));
+  assert!(dot.contains("  n2 -> r2 [label=\"WordBoundary\", style=dashed];\n"));
+  assert!(dot.contains("  r0 [shape=doublecircle, label=\"rule 0\"];\n"));
+
+  let mut steps = Vec::new();
+  let mat = tr.match_at_traced("-a12", 1, &mut |step| steps.push(step));
+  assert_eq!(mat, Some((RuleId(1), 4)));
+  assert_eq!(steps, vec![
+    ReTrace::Enter{node: 0, pos: 1, rule: None},
+    ReTrace::Enter{node: 1, pos: 2, rule: None},
+    ReTrace::Exit{node: 1, pos: 2, mat: Some((RuleId(1), 4))},
+    ReTrace::Exit{node: 0, pos: 1, mat: Some((RuleId(1), 4))},
+  ]);
+}